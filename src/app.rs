@@ -1,94 +1,218 @@
-use super::runtime::Runtime;
+use super::config::UiConfig;
+use super::runtime::{ConnectionState, Runtime};
 use super::ui;
+use super::ui::Control;
 use color_eyre::Result;
 use crossterm::event::{Event, EventStream, KeyCode, KeyEvent};
 use futures::StreamExt;
-use ratatui::style::Color;
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::prelude::*;
+use ratatui::widgets::Tabs;
 use ratatui::{DefaultTerminal, Frame};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
 
-pub struct App {
+// Signed in account with its own controls, so switching between accounts
+// preserves selected dialogs.
+struct Account {
+    name: String,
     app_runtime: Arc<Runtime>,
-    event_stream: EventStream,
-    should_run: bool,
     root_control: Box<dyn ui::Control>,
 }
-// TODO: remove after finish debugging.
-struct DummyControl {
-    color: Color,
-}
-
-impl ui::Control for DummyControl {
-    fn handle_keyboard(&mut self, _event: KeyEvent) -> Result<()> {
-        Ok(())
-    }
 
-    fn render(&mut self, frame: &mut Frame, rect: ratatui::layout::Rect) -> Result<()> {
-        let b =
-            ratatui::widgets::Block::new().style(ratatui::style::Style::default().bg(self.color));
-        frame.render_widget(b, rect);
-        Ok(())
-    }
+pub struct App {
+    accounts: Vec<Account>,
+    active_account: usize,
+    event_stream: EventStream,
+    // Runtimes of all accounts notify about changes to show.
+    redraw_receiver: Receiver<()>,
+    should_run: bool,
+    // Shown above root control, gets all keyboard input while open.
+    search_popup: Option<ui::SearchControl>,
+    ui_config: UiConfig,
 }
 
+const POPUP_SIZE_PERCENT: u16 = 80;
+// Delay before redraw requested by runtime, so changes coming together
+// cause one redraw.
+const REDRAW_DELAY: Duration = Duration::from_millis(50);
+
 impl App {
-    pub fn new(app_runtime: Arc<Runtime>) -> Self {
-        let left = Box::new(ui::DialogsListControl::new(app_runtime.clone()));
-        let right = Box::new(DummyControl { color: Color::Blue });
-        let root_control = ui::TwoPanelsControl::new(
-            left,
-            right,
-            Some("Dialogs".to_string()),
-            Some("Right panel".to_string()),
-        );
+    /// `accounts` are pairs of account name and its runtime, there must
+    /// be at least one.
+    pub fn new(
+        accounts: Vec<(String, Arc<Runtime>)>,
+        redraw_receiver: Receiver<()>,
+        ui_config: UiConfig,
+    ) -> Self {
+        let accounts = accounts
+            .into_iter()
+            .map(|(name, app_runtime)| {
+                let left = Box::new(ui::DialogsListControl::new(app_runtime.clone()));
+                let right = Box::new(ui::ChatControl::new(app_runtime.clone(), &ui_config));
+                let root_control = ui::TwoPanelsControl::new(
+                    left,
+                    right,
+                    Some("Dialogs".to_string()),
+                    Some("Messages".to_string()),
+                    ui_config.dialogs_width_percent,
+                );
+                Account {
+                    name,
+                    app_runtime,
+                    root_control: Box::new(root_control),
+                }
+            })
+            .collect();
         Self {
-            app_runtime,
+            accounts,
+            active_account: 0,
             event_stream: EventStream::new(),
+            redraw_receiver,
             should_run: true,
-            root_control: Box::new(root_control),
+            search_popup: None,
+            ui_config,
         }
     }
 
     pub async fn run(&mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        // TODO(vchigrin): Remove after implementing Messages list.
-        let dialogs = self.app_runtime.get_dialogs()?;
-        if !dialogs.is_empty() {
-            self.app_runtime
-                .start_message_refreshing(dialogs[0].chat.clone())
-                .await?;
-        }
         while self.should_run {
             terminal.draw(|frame| self.render(frame))?;
-            if let Some(read_result) = self.event_stream.next().await {
-                match read_result {
-                    Err(e) => {
-                        log::error!("Keyboard read failed {:?}", e);
-                        self.should_run = true;
+            tokio::select! {
+                maybe_read_result = self.event_stream.next() => {
+                    let Some(read_result) = maybe_read_result else {
                         break;
-                    }
-                    Ok(event) => {
-                        if let Event::Key(kbd_event) = event {
-                            // TODO(vchigrin): Remove this hardcode.
-                            if kbd_event.code == KeyCode::Esc {
-                                self.should_run = false;
-                                break;
-                            }
-                            if let Err(e) = self.root_control.handle_keyboard(kbd_event) {
-                                log::error!("Failed handle keyboard; Error {:?}", e);
+                    };
+                    match read_result {
+                        Err(e) => {
+                            log::error!("Keyboard read failed {:?}", e);
+                            self.should_run = true;
+                            break;
+                        }
+                        Ok(event) => {
+                            if let Event::Key(kbd_event) = event {
+                                if let Err(e) = self.handle_keyboard(kbd_event) {
+                                    log::error!("Failed handle keyboard; Error {:?}", e);
+                                }
                             }
                         }
                     }
+                },
+                Some(()) = self.redraw_receiver.recv() => {
+                    // Don't redraw too often while updates keep coming.
+                    tokio::time::sleep(REDRAW_DELAY).await;
+                    while self.redraw_receiver.try_recv().is_ok() {}
                 }
-            } else {
-                break;
             }
         }
         Ok(())
     }
 
+    fn handle_keyboard(&mut self, kbd_event: KeyEvent) -> Result<()> {
+        if let Some(search_popup) = self.search_popup.as_mut() {
+            search_popup.handle_keyboard(kbd_event)?;
+            if search_popup.is_closed() {
+                self.search_popup = None;
+            }
+            return Ok(());
+        }
+        let account = &mut self.accounts[self.active_account];
+        if !account.root_control.captures_keyboard() {
+            match kbd_event.code {
+                // TODO(vchigrin): Remove this hardcode.
+                KeyCode::Esc => {
+                    self.should_run = false;
+                    return Ok(());
+                }
+                KeyCode::Char('/') => {
+                    self.search_popup = Some(ui::SearchControl::new(
+                        account.app_runtime.clone(),
+                        &self.ui_config,
+                    ));
+                    return Ok(());
+                }
+                KeyCode::Char(']') => {
+                    account.root_control.hide_images();
+                    self.active_account = (self.active_account + 1) % self.accounts.len();
+                    return Ok(());
+                }
+                KeyCode::Char('[') => {
+                    account.root_control.hide_images();
+                    self.active_account =
+                        (self.active_account + self.accounts.len() - 1) % self.accounts.len();
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        account.root_control.handle_keyboard(kbd_event)
+    }
+
     fn render(&mut self, frame: &mut Frame) {
-        if let Err(e) = self.root_control.render(frame, frame.area()) {
+        let [status_area, root_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(frame.area());
+        let status = self.make_connection_status();
+        let [accounts_area, connection_area] = Layout::horizontal([
+            Constraint::Min(0),
+            Constraint::Length(status.width() as u16),
+        ])
+        .areas(status_area);
+        // Account switcher is shown only if there is something to switch.
+        if self.accounts.len() > 1 {
+            self.render_accounts(frame, accounts_area);
+        }
+        frame.render_widget(status, connection_area);
+        let account = &mut self.accounts[self.active_account];
+        if let Err(e) = account.root_control.render(frame, root_area) {
             log::error!("Failed render; Error {:?}", e);
         }
+        if self.search_popup.is_some() {
+            account.root_control.hide_images();
+        } else if let Err(e) = account.root_control.render_images(frame, root_area) {
+            log::error!("Failed render images; Error {:?}", e);
+        }
+        if let Some(search_popup) = self.search_popup.as_mut() {
+            let [popup_area] = Layout::horizontal([Constraint::Percentage(POPUP_SIZE_PERCENT)])
+                .flex(Flex::Center)
+                .areas(frame.area());
+            let [popup_area] = Layout::vertical([Constraint::Percentage(POPUP_SIZE_PERCENT)])
+                .flex(Flex::Center)
+                .areas(popup_area);
+            if let Err(e) = search_popup.render(frame, popup_area) {
+                log::error!("Failed render search; Error {:?}", e);
+            }
+        }
+    }
+
+    // Connection state of the active account.
+    fn make_connection_status(&self) -> Line<'static> {
+        let app_runtime = &self.accounts[self.active_account].app_runtime;
+        let (text, style) = match app_runtime.get_connection_state() {
+            ConnectionState::Connecting => ("Connecting...", Style::new().yellow()),
+            ConnectionState::Updating => ("Updating...", Style::new().yellow()),
+            ConnectionState::Online => ("Online", Style::new().green()),
+            ConnectionState::Offline => ("Offline", Style::new().red()),
+        };
+        Line::from(format!("● {}", text)).style(style)
+    }
+
+    fn render_accounts(&self, frame: &mut Frame, rect: Rect) {
+        let titles = self.accounts.iter().map(|account| {
+            let mut components = vec![Span::from(account.name.clone())];
+            match account.app_runtime.get_unread_count() {
+                Ok(0) => {}
+                Ok(count) => {
+                    components.push(Span::from(format!(" {}", count)).style(Style::new().red()));
+                }
+                Err(e) => log::error!("Failed get unread count; Error {:?}", e),
+            }
+            Line::from(components)
+        });
+        let tabs = Tabs::new(titles)
+            .style(Style::new().white())
+            .highlight_style(Style::new().yellow().bold())
+            .select(self.active_account);
+        frame.render_widget(tabs, rect);
     }
 }
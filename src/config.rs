@@ -0,0 +1,287 @@
+use clap::Parser;
+use color_eyre::Result;
+use eyre::eyre;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const APP_DIR_NAME: &str = "geekgram";
+const CONFIG_FILE_NAME: &str = "config.toml";
+const DATABASE_FILE_NAME: &str = "geekgram.db";
+const DEFAULT_ACCOUNT_NAME: &str = "default";
+const LOG_FILE_NAME: &str = "geekgram.log";
+const MEDIA_CACHE_DIR_NAME: &str = "media";
+
+/// Terminal Telegram client.
+#[derive(Parser)]
+#[command(version, about)]
+struct CommandLine {
+    /// Path to the config file [default: $XDG_CONFIG_HOME/geekgram/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Path to the database with session and cached messages, when
+    /// accounts are not listed in the config file
+    #[arg(long)]
+    database: Option<PathBuf>,
+    /// Path to the log file
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Directory for downloaded media files
+    #[arg(long)]
+    media_cache_dir: Option<PathBuf>,
+    /// One of off, error, warn, info, debug, trace
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Telegram API ID, overrides TG_ID environment variable
+    #[arg(long)]
+    api_id: Option<i32>,
+    /// Telegram API hash, overrides TG_HASH environment variable
+    #[arg(long)]
+    api_hash: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    database: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    media_cache_dir: Option<PathBuf>,
+    log_level: Option<String>,
+    api_id: Option<i32>,
+    api_hash: Option<String>,
+    accounts: Vec<AccountFileEntry>,
+    ui: UiConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountFileEntry {
+    name: String,
+    database: Option<PathBuf>,
+}
+
+/// How images are drawn in the terminal.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageProtocol {
+    /// Detected from the terminal environment.
+    Auto,
+    /// Unicode half-block characters, supported by any terminal with
+    /// true colors.
+    HalfBlocks,
+    Kitty,
+    Sixel,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// Initial width of the dialogs panel in percents of the screen width.
+    pub dialogs_width_percent: u16,
+    /// Format of message dates, as accepted by chrono::format::strftime.
+    pub date_format: String,
+    /// Protocol of inline image previews and the full-screen image viewer.
+    pub image_protocol: ImageProtocol,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            dialogs_width_percent: 50,
+            date_format: "%Y-%m-%d %H:%M".to_string(),
+            image_protocol: ImageProtocol::Auto,
+        }
+    }
+}
+
+/// Credentials of the application, registered at https://my.telegram.org.
+#[derive(Clone)]
+pub struct ApiCredentials {
+    pub api_id: i32,
+    pub api_hash: String,
+}
+
+/// Telegram account, signed in with its own session and cached data.
+pub struct AccountConfig {
+    pub name: String,
+    pub database_path: PathBuf,
+}
+
+/// Application settings, merged from the command line, the config file
+/// and defaults, in that order of priority. API credentials may also be
+/// set by environment variables, that take priority over the config file.
+pub struct Config {
+    pub accounts: Vec<AccountConfig>,
+    pub log_path: PathBuf,
+    // Shared by all accounts, as files in it are named by their content.
+    pub media_cache_dir: PathBuf,
+    pub log_level: log::LevelFilter,
+    pub api_credentials: ApiCredentials,
+    pub ui: UiConfig,
+}
+
+const API_ID_ENV_VAR: &str = "TG_ID";
+const API_HASH_ENV_VAR: &str = "TG_HASH";
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let command_line = CommandLine::parse();
+        let config_file = match command_line.config.as_ref() {
+            // Explicitly specified file must exist.
+            Some(path) => Self::read_config_file(path)?,
+            None => {
+                let path = xdg_dir("XDG_CONFIG_HOME", ".config")?
+                    .join(APP_DIR_NAME)
+                    .join(CONFIG_FILE_NAME);
+                if path.exists() {
+                    Self::read_config_file(&path)?
+                } else {
+                    ConfigFile::default()
+                }
+            }
+        };
+        let accounts = Self::make_accounts(
+            command_line.database.or(config_file.database),
+            config_file.accounts,
+        )?;
+        let log_path = match command_line.log_file.or(config_file.log_file) {
+            Some(path) => path,
+            None => xdg_dir("XDG_STATE_HOME", ".local/state")?
+                .join(APP_DIR_NAME)
+                .join(LOG_FILE_NAME),
+        };
+        let media_cache_dir = match command_line.media_cache_dir.or(config_file.media_cache_dir) {
+            Some(path) => path,
+            None => xdg_dir("XDG_CACHE_HOME", ".cache")?
+                .join(APP_DIR_NAME)
+                .join(MEDIA_CACHE_DIR_NAME),
+        };
+        let log_level = match command_line.log_level.or(config_file.log_level) {
+            Some(level) => log::LevelFilter::from_str(&level)
+                .map_err(|_| eyre!("Invalid log level \"{}\"", level))?,
+            None => log::LevelFilter::Debug,
+        };
+        let api_id = match command_line.api_id {
+            Some(api_id) => Some(api_id),
+            None => match std::env::var(API_ID_ENV_VAR) {
+                Ok(value) => Some(value.trim().parse::<i32>().map_err(|_| {
+                    eyre!(
+                        "Invalid {} environment variable \"{}\"",
+                        API_ID_ENV_VAR,
+                        value
+                    )
+                })?),
+                Err(_) => config_file.api_id,
+            },
+        };
+        let api_hash = command_line
+            .api_hash
+            .or_else(|| std::env::var(API_HASH_ENV_VAR).ok())
+            .or(config_file.api_hash);
+        let (Some(api_id), Some(api_hash)) = (api_id, api_hash) else {
+            return Err(eyre!(
+                "Telegram API credentials are not set. Get them at https://my.telegram.org \
+                 and pass with --api-id and --api-hash, set {} and {} environment variables, \
+                 or add api_id and api_hash to the config file",
+                API_ID_ENV_VAR,
+                API_HASH_ENV_VAR
+            ));
+        };
+        let ui = config_file.ui;
+        if !(1..100).contains(&ui.dialogs_width_percent) {
+            return Err(eyre!(
+                "ui.dialogs_width_percent must be between 1 and 99, got {}",
+                ui.dialogs_width_percent
+            ));
+        }
+        let result = Self {
+            accounts,
+            log_path,
+            media_cache_dir,
+            log_level,
+            api_credentials: ApiCredentials { api_id, api_hash },
+            ui,
+        };
+        result.create_dirs()?;
+        Ok(result)
+    }
+
+    fn read_config_file(path: &Path) -> Result<ConfigFile> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed read config file {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| eyre!("Failed parse config file {}: {}", path.display(), e))
+    }
+
+    // Without accounts in the config file there is the only account, that
+    // uses `database` path.
+    fn make_accounts(
+        database: Option<PathBuf>,
+        account_entries: Vec<AccountFileEntry>,
+    ) -> Result<Vec<AccountConfig>> {
+        let data_dir = xdg_dir("XDG_DATA_HOME", ".local/share")?.join(APP_DIR_NAME);
+        if account_entries.is_empty() {
+            return Ok(vec![AccountConfig {
+                name: DEFAULT_ACCOUNT_NAME.to_string(),
+                database_path: database.unwrap_or_else(|| data_dir.join(DATABASE_FILE_NAME)),
+            }]);
+        }
+        if database.is_some() {
+            return Err(eyre!(
+                "Database path can not be set when accounts are listed in the config file, \
+                 set database of each account instead"
+            ));
+        }
+        let mut result: Vec<AccountConfig> = Vec::with_capacity(account_entries.len());
+        for entry in account_entries {
+            if entry.name.is_empty() || entry.name.contains(std::path::is_separator) {
+                return Err(eyre!("Invalid account name \"{}\"", entry.name));
+            }
+            if result.iter().any(|account| account.name == entry.name) {
+                return Err(eyre!("Duplicate account name \"{}\"", entry.name));
+            }
+            let database_path = entry
+                .database
+                .unwrap_or_else(|| data_dir.join(format!("{}.db", entry.name)));
+            if let Some(other) = result
+                .iter()
+                .find(|account| account.database_path == database_path)
+            {
+                return Err(eyre!(
+                    "Accounts \"{}\" and \"{}\" use the same database",
+                    other.name,
+                    entry.name
+                ));
+            }
+            result.push(AccountConfig {
+                name: entry.name,
+                database_path,
+            });
+        }
+        Ok(result)
+    }
+
+    fn create_dirs(&self) -> Result<()> {
+        let database_paths = self.accounts.iter().map(|account| &account.database_path);
+        for path in database_paths.chain([&self.log_path]) {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Returns base directory from XDG environment variable, or its default
+// location relative to the home directory.
+fn xdg_dir(env_var: &str, default_relative_to_home: &str) -> Result<PathBuf> {
+    if let Some(value) = std::env::var_os(env_var) {
+        let path = PathBuf::from(value);
+        // Relative paths are invalid according to the XDG spec.
+        if path.is_absolute() {
+            return Ok(path);
+        }
+    }
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME is not set"))?;
+    Ok(PathBuf::from(home).join(default_relative_to_home))
+}
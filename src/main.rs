@@ -1,36 +1,96 @@
 use color_eyre::Result;
-use std::path::Path;
 use std::sync::Arc;
+use tg_client_builder::SignInResult;
 use tokio::runtime as tr;
+use tokio::sync::mpsc::channel;
 
 mod app;
+mod config;
+mod media_cache;
 mod runtime;
+mod scheduler;
 mod storage;
 mod tg_client_builder;
 mod ui;
 
+// Returns storage and client of every account, except ones user cancelled
+// signing in to. Client is None for accounts, that start offline.
+async fn sign_in_accounts(
+    config: &config::Config,
+    terminal: &mut ratatui::DefaultTerminal,
+) -> Result<Vec<(String, storage::Storage, Option<grammers_client::Client>)>> {
+    let mut result = Vec::new();
+    let show_names = config.accounts.len() > 1;
+    for account in config.accounts.iter() {
+        let storage = storage::Storage::new(&account.database_path)?;
+        let sign_in_result = tg_client_builder::TgClientBuilder::make_signed_in_client(
+            show_names.then_some(account.name.as_str()),
+            &storage,
+            &config.api_credentials,
+            terminal,
+        )
+        .await?;
+        match sign_in_result {
+            SignInResult::SignedIn(tg_client) => {
+                result.push((account.name.clone(), storage, Some(tg_client)))
+            }
+            SignInResult::Offline => result.push((account.name.clone(), storage, None)),
+            SignInResult::Cancelled => {
+                log::info!("Sign in to account {} cancelled", account.name)
+            }
+        }
+    }
+    Ok(result)
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
-    simple_logging::log_to_file("/tmp/geekgram.log", log::LevelFilter::Debug).unwrap();
-    let storage = storage::Storage::new(Path::new("/tmp/geekgram.db"))?;
+    let config = config::Config::load()?;
+    simple_logging::log_to_file(&config.log_path, config.log_level)?;
+    let media_cache = media_cache::MediaCache::new(config.media_cache_dir.clone())?;
     let tokio_rt = tr::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
-    let tg_client = tokio_rt.block_on(
-        tg_client_builder::TgClientBuilder::make_signed_in_client(&storage),
-    )?;
-    let app_runtime = Arc::new(runtime::Runtime::new(storage, tg_client, &tokio_rt));
-    let mut app = app::App::new(app_runtime.clone());
-    let terminal = ratatui::init();
+    let mut terminal = ratatui::init();
+    let sign_in_result = tokio_rt.block_on(sign_in_accounts(&config, &mut terminal));
+    let signed_in_accounts = match sign_in_result {
+        Ok(accounts) if !accounts.is_empty() => accounts,
+        Ok(_) => {
+            ratatui::restore();
+            return Ok(());
+        }
+        Err(e) => {
+            ratatui::restore();
+            return Err(e);
+        }
+    };
+    let (redraw_sender, redraw_receiver) = channel::<()>(1);
+    let accounts: Vec<(String, Arc<runtime::Runtime>)> = signed_in_accounts
+        .into_iter()
+        .map(|(name, storage, tg_client)| {
+            let app_runtime = runtime::Runtime::new(
+                storage,
+                tg_client,
+                config.api_credentials.clone(),
+                media_cache.clone(),
+                redraw_sender.clone(),
+                &tokio_rt,
+            );
+            (name, Arc::new(app_runtime))
+        })
+        .collect();
+    let mut app = app::App::new(accounts.clone(), redraw_receiver, config.ui);
     let result = tokio_rt.block_on(app.run(terminal));
     drop(app);
-    // Only one reference must remain here after App destruction.
-    let only_one_runtime =
-        Arc::try_unwrap(app_runtime).unwrap_or_else(|_| panic!("Unexpected reference"));
-    let stop_result = tokio_rt.block_on(only_one_runtime.stop());
-    if let Err(e) = stop_result {
-        log::error!("Error during stopping runtime. {:?}", e);
+    for (name, app_runtime) in accounts {
+        // Only one reference must remain here after App destruction.
+        let only_one_runtime =
+            Arc::try_unwrap(app_runtime).unwrap_or_else(|_| panic!("Unexpected reference"));
+        let stop_result = tokio_rt.block_on(only_one_runtime.stop());
+        if let Err(e) = stop_result {
+            log::error!("Error during stopping runtime of {}. {:?}", name, e);
+        }
     }
     ratatui::restore();
     result
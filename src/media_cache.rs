@@ -0,0 +1,131 @@
+use super::storage::MediaFile;
+use color_eyre::Result;
+use grammers_client::types::Media;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Subdirectory of files, which download is not complete yet.
+const PARTIAL_DIR_NAME: &str = "partial";
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Directory with downloaded media files, named by hashes of their
+/// content, so media forwarded many times is stored once.
+#[derive(Clone)]
+pub struct MediaCache {
+    dir: PathBuf,
+}
+
+impl MediaCache {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(dir.join(PARTIAL_DIR_NAME))?;
+        Ok(Self { dir })
+    }
+
+    /// Returns path of the cached file.
+    pub fn file_path(&self, media_file: &MediaFile) -> PathBuf {
+        // Extension is kept, so other programs can open the file.
+        let file_name = match Path::new(&media_file.file_name).extension() {
+            Some(extension) => format!(
+                "{}.{}",
+                media_file.content_hash,
+                extension.to_string_lossy()
+            ),
+            None => media_file.content_hash.clone(),
+        };
+        self.dir.join(file_name)
+    }
+
+    /// Returns path, where the media is downloaded to, until it is complete.
+    /// Download is resumed from it after failures and restarts.
+    pub fn partial_path(&self, media_key: &str) -> PathBuf {
+        self.dir
+            .join(PARTIAL_DIR_NAME)
+            .join(format!("{}.part", media_key))
+    }
+
+    /// Moves completely downloaded media into the cache.
+    pub fn add_downloaded(&self, media_key: &str, file_name: &str) -> Result<MediaFile> {
+        let partial_path = self.partial_path(media_key);
+        let (content_hash, size) = Self::hash_file(&partial_path)?;
+        let media_file = MediaFile {
+            content_hash,
+            file_name: file_name.to_string(),
+            size,
+        };
+        let path = self.file_path(&media_file);
+        if path.exists() {
+            // Same content was downloaded for other media.
+            std::fs::remove_file(&partial_path)?;
+        } else {
+            std::fs::rename(&partial_path, &path)?;
+        }
+        Ok(media_file)
+    }
+
+    // Returns hex encoded SHA-256 of the file content and its size.
+    fn hash_file(path: &Path) -> Result<(String, u64)> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; HASH_BUFFER_SIZE];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        Ok((format!("{:x}", hasher.finalize()), size))
+    }
+}
+
+/// Returns key, that identifies media file on Telegram servers, or None if
+/// the media has no file to download.
+pub fn media_key(media: &Media) -> Option<String> {
+    media.to_raw_input_location()?;
+    match media {
+        Media::Photo(photo) => Some(format!("photo{}", photo.id())),
+        Media::Document(document) => Some(format!("document{}", document.id())),
+        Media::Sticker(sticker) => Some(format!("document{}", sticker.document.id())),
+        _ => None,
+    }
+}
+
+/// Returns size of the media file, if known.
+pub fn media_size(media: &Media) -> Option<u64> {
+    let size = match media {
+        Media::Photo(photo) => photo.size(),
+        Media::Document(document) => document.size(),
+        Media::Sticker(sticker) => sticker.document.size(),
+        _ => return None,
+    };
+    u64::try_from(size).ok().filter(|size| *size > 0)
+}
+
+/// Returns name of the media file, as it was sent, or made up from its
+/// type, if the file has no name.
+pub fn media_file_name(media: &Media) -> String {
+    let document = match media {
+        Media::Document(document) => document,
+        Media::Sticker(sticker) => &sticker.document,
+        _ => return "photo.jpg".to_string(),
+    };
+    if !document.name().is_empty() {
+        return document.name().to_string();
+    }
+    let extension = match document.mime_type().unwrap_or_default() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "audio/ogg" => "ogg",
+        "audio/mpeg" => "mp3",
+        "application/x-tgsticker" => "tgs",
+        _ => "bin",
+    };
+    format!("file.{}", extension)
+}
@@ -1,94 +1,1146 @@
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
-use grammers_client::types::{Chat, Dialog};
-use grammers_client::{Client, Update};
+use eyre::eyre;
+use futures::FutureExt;
+use grammers_client::client::files::DownloadIter;
+use grammers_client::client::messages::{GlobalSearchIter, SearchIter};
+use grammers_client::session::{PackedChat, UpdateState};
+use grammers_client::types::{Attribute, Chat, Dialog, Downloadable, Media, Message};
+use grammers_client::{ChatMap, Client, InputMessage, InvocationError, Update};
+use grammers_tl_types as tl_types;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWriteExt, ReadBuf};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
 
+use super::config::ApiCredentials;
+use super::media_cache::{self, MediaCache};
+use super::scheduler::{self, Priority, RequestScheduler};
 use super::storage;
+use super::storage::{OutboxMessage, OutboxState, StoredMessage};
+use super::tg_client_builder::{TgClientBuilder, CONNECT_TIMEOUT};
 
 #[derive(Debug)]
 enum Command {
+    // Fetches page of dialogs after the offset, or the first one, and
+    // continues with the next page, until all dialogs are fetched. Used when
+    // there are no cached ones or cached ones can't be updated by missed
+    // updates.
+    LoadDialogs(Option<DialogsOffset>),
+    // Fetches page of dialogs with the newest messages.
+    RefreshDialogs,
+    // Checks whether too many updates were missed since the saved pts for
+    // grammers to catch up with them.
+    CheckMissedUpdates {
+        pts: i32,
+    },
     RefreshMessages(Chat),
+    // Loads portion of messages with IDs less than specified one.
+    LoadOlderMessages(Chat, i32),
+    // Sends pending outbox messages.
+    SendOutbox,
+    // Chats are packed to keep the command small.
+    Forward {
+        from: PackedChat,
+        message_id: i32,
+        to: PackedChat,
+    },
+    Edit {
+        chat: Chat,
+        message_id: i32,
+        text: String,
+    },
+    Delete {
+        chat: Chat,
+        message_id: i32,
+        // Delete for all participants, not only for the user.
+        revoke: bool,
+    },
+    MarkAsRead(Chat),
+    // Starts download of the message media in background. The message is
+    // fetched again, when file reference of the media expires.
+    Download {
+        media: Box<Media>,
+        chat: PackedChat,
+        message_id: i32,
+    },
+    // Starts upload of the file with the ID in background.
+    SendFile(u64),
+    // Searches messages on server, in one chat or in all of them.
+    Search {
+        generation: u64,
+        text: String,
+        chat: Option<Chat>,
+    },
+}
+
+// Position in the dialogs list, ordered by date of their top messages.
+#[derive(Debug)]
+struct DialogsOffset {
+    date: i32,
+    message_id: i32,
+    peer: PackedChat,
+}
+
+impl Command {
+    fn priority(&self) -> Priority {
+        match self {
+            Command::LoadDialogs(_) | Command::LoadOlderMessages(..) => Priority::Background,
+            Command::RefreshDialogs
+            | Command::CheckMissedUpdates { .. }
+            | Command::RefreshMessages(_)
+            | Command::SendOutbox
+            | Command::Forward { .. }
+            | Command::Edit { .. }
+            | Command::Delete { .. }
+            | Command::MarkAsRead(_)
+            | Command::Download { .. }
+            | Command::SendFile(_)
+            | Command::Search { .. } => Priority::Interactive,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ServerSearch {
+    // Incremented by every new search, so results of outdated ones are dropped.
+    generation: u64,
+    results: Vec<StoredMessage>,
+    in_progress: bool,
+    // Task fetching results of the current search.
+    abort_handle: Option<AbortHandle>,
+}
+
+// Messages found by server, in one chat or in all of them.
+enum SearchResults {
+    Chat(SearchIter),
+    Global(GlobalSearchIter),
+}
+
+impl SearchResults {
+    fn new(tg_client: &Client, text: &str, chat: Option<&Chat>) -> Self {
+        match chat {
+            Some(chat) => Self::Chat(
+                tg_client
+                    .search_messages(chat)
+                    .query(text)
+                    .limit(SERVER_SEARCH_LIMIT),
+            ),
+            None => Self::Global(
+                tg_client
+                    .search_all_messages()
+                    .query(text)
+                    .limit(SERVER_SEARCH_LIMIT),
+            ),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<Message>, InvocationError> {
+        match self {
+            Self::Chat(messages) => messages.next().await,
+            Self::Global(messages) => messages.next().await,
+        }
+    }
+}
+
+/// State of the connection to Telegram servers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+    Connecting,
+    // Connected, fetching changes made while the app was offline.
+    Updating,
+    Online,
+    // Connection failed or lost, it is retried after a delay.
+    Offline,
+}
+
+#[derive(Clone, Debug)]
+pub enum DownloadState {
+    // Waits for other downloads to finish.
+    Queued,
+    Downloading,
+    // Contains path of the downloaded file.
+    Done(PathBuf),
+    // Download may be resumed by requesting it again.
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct DownloadProgress {
+    pub state: DownloadState,
+    pub downloaded: u64,
+    // None if size of the media is unknown.
+    pub total: Option<u64>,
+}
+
+/// How the sent file is shown in the chat.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileKind {
+    Document,
+    // Compressed by Telegram.
+    Photo,
+    Audio,
+}
+
+impl FileKind {
+    /// Guesses kind by extension of the file.
+    pub fn for_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("jpg" | "jpeg" | "png" | "webp") => FileKind::Photo,
+            Some("mp3" | "m4a" | "ogg" | "flac" | "wav") => FileKind::Audio,
+            _ => FileKind::Document,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            FileKind::Document => FileKind::Photo,
+            FileKind::Photo => FileKind::Audio,
+            FileKind::Audio => FileKind::Document,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FileKind::Document => "file",
+            FileKind::Photo => "photo",
+            FileKind::Audio => "audio",
+        }
+    }
+}
+
+/// File being sent to the active chat.
+pub struct UploadProgress {
+    pub id: u64,
+    pub file_name: String,
+    pub kind: FileKind,
+    pub caption: String,
+    pub uploaded: u64,
+    pub total: u64,
+    // Upload may be retried or cancelled by user.
+    pub failed: bool,
+}
+
+struct Upload {
+    id: u64,
+    chat: PackedChat,
+    path: PathBuf,
+    kind: FileKind,
+    caption: String,
+    // Updated by the upload task.
+    uploaded: Arc<AtomicU64>,
+    total: u64,
+    failed: bool,
+    // Set while the upload task runs.
+    abort_handle: Option<AbortHandle>,
+}
+
+// Counts bytes read from the file being uploaded. grammers reads the file
+// part by part right before sending them, so it is the upload progress.
+struct ProgressReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+    redraw_sender: Sender<()>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled_before;
+        if read > 0 {
+            self.read.fetch_add(read as u64, Ordering::Relaxed);
+            Runtime::request_redraw(&self.redraw_sender);
+        }
+        result
+    }
+}
+
+enum ServeResult {
+    // Runtime is stopped.
+    Stopped,
+    Disconnected,
 }
 
 struct SharedState {
     storage: storage::Storage,
+    // Chat, which messages are shown to the user.
+    active_chat: Option<Chat>,
+    // Message of the active chat to show instead of the newest ones,
+    // e.g. found by search.
+    active_chat_anchor: Option<i32>,
+    // Newest message of the active chat, that was read before the chat
+    // was opened.
+    active_chat_read_inbox_max_id: i32,
+    // Chat ID and offset ID of the history portion being loaded, to avoid
+    // requesting it many times while user scrolls.
+    pending_history_request: Option<(i64, i32)>,
+    // Chat ID of the queued request to mark new messages as read, so many
+    // messages in a row are marked by one request.
+    pending_read_chat: Option<i64>,
+    // Dates of messages by marked peer IDs and message IDs, as dialogs are
+    // sorted by dates of their top messages on every redraw.
+    message_dates: HashMap<(i64, i32), DateTime<Utc>>,
+    server_search: ServerSearch,
+    connection_state: ConnectionState,
+    media_cache: MediaCache,
+    // Downloads started since the app start and files found in the media
+    // cache, by media keys.
+    downloads: HashMap<String, DownloadProgress>,
+    // Keys of media, that is not in the media cache, so it is not looked
+    // up again on every redraw.
+    missing_media: HashSet<String>,
+    // Limits count of downloads running at once.
+    download_slots: Arc<Semaphore>,
+    // Files being sent, in order they were chosen.
+    uploads: Vec<Upload>,
+    next_upload_id: u64,
+}
+
+impl SharedState {
+    fn remember_message_date(&mut self, message: &Message) {
+        let key = (storage::peer_id(&message.raw.peer_id), message.id());
+        self.message_dates.insert(key, message.date());
+    }
+}
+
+pub struct ChatReadState {
+    // Incoming messages with greater IDs are new for the user.
+    pub read_inbox_max_id: i32,
+    // Outgoing messages with IDs up to this one are read by the peer.
+    pub read_outbox_max_id: i32,
 }
 
 pub struct Runtime {
     shared_state: Arc<Mutex<SharedState>>,
     update_loop_handle: tokio::task::JoinHandle<()>,
     command_sender: Sender<Command>,
+    redraw_sender: Sender<()>,
 }
 
 const COMMAND_BUFFER_SIZE: usize = 10;
 const MESSAGES_PORTION_SIZE: usize = 20;
+// How many messages newer than the anchor are shown.
+const MESSAGES_AFTER_ANCHOR: usize = 25;
+const SERVER_SEARCH_LIMIT: usize = 100;
+// Telegram returns at most that many dialogs per request.
+const DIALOGS_PAGE_SIZE: usize = 100;
+// How often update state is saved.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+// Delay before reconnection doubles after every failure, up to the max.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Missed updates are considered fetched, when no updates come that long.
+const CATCH_UP_QUIET_TIME: Duration = Duration::from_secs(1);
+// Server returns only the new pts instead of updates, when too many were
+// missed. Its limit is not documented, so dialogs are reloaded after less
+// missed updates, which is cheap.
+const MAX_CAUGHT_UP_PTS: i32 = 1000;
+// grammers fetches missed updates of a channel by that many messages, and
+// when more were missed, server returns only the new pts of the channel.
+const CHANNEL_DIFFERENCE_LIMIT: i32 = 100;
+const MAX_PARALLEL_DOWNLOADS: usize = 3;
+// Partially downloaded files are resumed from the last complete chunk.
+const DOWNLOAD_CHUNK_SIZE: i32 = 512 * 1024;
 
 impl Runtime {
+    /// `redraw_sender` gets notified when data, shown to the user, changes.
+    /// If `tg_client` is None, cached data is shown until runtime connects
+    /// with the session from `storage`.
     pub fn new(
         storage: storage::Storage,
-        tg_client: Client,
+        tg_client: Option<Client>,
+        api_credentials: ApiCredentials,
+        media_cache: MediaCache,
+        redraw_sender: Sender<()>,
         tokio_rt: &tokio::runtime::Runtime,
     ) -> Self {
         let (sender, receiver) = channel::<Command>(COMMAND_BUFFER_SIZE);
-        let shared_state = SharedState { storage };
+        let shared_state = SharedState {
+            storage,
+            active_chat: None,
+            active_chat_anchor: None,
+            active_chat_read_inbox_max_id: 0,
+            pending_history_request: None,
+            pending_read_chat: None,
+            message_dates: HashMap::new(),
+            server_search: ServerSearch::default(),
+            connection_state: ConnectionState::Connecting,
+            media_cache,
+            downloads: HashMap::new(),
+            missing_media: HashSet::new(),
+            download_slots: Arc::new(Semaphore::new(MAX_PARALLEL_DOWNLOADS)),
+            uploads: Vec::new(),
+            next_upload_id: 1,
+        };
         let wrapped_shared_state = Arc::new(Mutex::new(shared_state));
         let update_loop_handle = tokio_rt.spawn(Self::update_loop(
             wrapped_shared_state.clone(),
             tg_client,
+            api_credentials,
             receiver,
+            redraw_sender.clone(),
         ));
         Self {
             shared_state: wrapped_shared_state,
             update_loop_handle,
             command_sender: sender,
+            redraw_sender,
         }
     }
 
     async fn update_loop(
         shared_state: Arc<Mutex<SharedState>>,
-        tg_client: Client,
+        mut tg_client: Option<Client>,
+        api_credentials: ApiCredentials,
         mut command_receiver: Receiver<Command>,
+        redraw_sender: Sender<()>,
     ) {
-        Self::do_initial_update(&shared_state, &tg_client)
+        // Kept across reconnections, so commands, that came while offline,
+        // are handled once connected.
+        let mut scheduler = RequestScheduler::new();
+        let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            if let Some(client) = tg_client.take() {
+                let result = Self::run_connected(
+                    &shared_state,
+                    client,
+                    &mut command_receiver,
+                    &mut scheduler,
+                    &mut reconnect_delay,
+                    &redraw_sender,
+                )
+                .await;
+                if let ServeResult::Stopped = result {
+                    return;
+                }
+                if !Self::wait_reconnect(
+                    &mut reconnect_delay,
+                    &shared_state,
+                    &mut command_receiver,
+                    &mut scheduler,
+                    &redraw_sender,
+                )
+                .await
+                {
+                    return;
+                }
+            }
+            Self::set_connection_state(&shared_state, ConnectionState::Connecting, &redraw_sender);
+            let Some(connect_result) = Self::wait_keeping_commands(
+                Self::connect(&shared_state, &api_credentials),
+                &mut command_receiver,
+                &mut scheduler,
+            )
             .await
-            .unwrap();
+            else {
+                return;
+            };
+            match connect_result {
+                Ok(client) => tg_client = Some(client),
+                Err(e) => {
+                    log::error!("Failed connect {:?}", e);
+                    if !Self::wait_reconnect(
+                        &mut reconnect_delay,
+                        &shared_state,
+                        &mut command_receiver,
+                        &mut scheduler,
+                        &redraw_sender,
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Handles commands and updates until runtime is stopped or the
+    // connection is lost.
+    async fn run_connected(
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: Client,
+        command_receiver: &mut Receiver<Command>,
+        scheduler: &mut RequestScheduler<Command>,
+        reconnect_delay: &mut Duration,
+        redraw_sender: &Sender<()>,
+    ) -> ServeResult {
+        *reconnect_delay = INITIAL_RECONNECT_DELAY;
+        Self::set_connection_state(shared_state, ConnectionState::Updating, redraw_sender);
+        match Self::load_update_state(shared_state) {
+            // Changes since the last run come as updates from the saved
+            // update state, which grammers fetches, unless there are too
+            // many of them.
+            Ok(Some(state)) => {
+                log::info!("Catching up with updates since {:?}", state);
+                let command = Command::CheckMissedUpdates { pts: state.pts };
+                scheduler.push(command, Priority::Interactive);
+            }
+            Ok(None) => scheduler.push(Command::LoadDialogs(None), Priority::Background),
+            Err(e) => log::error!("Failed load update state {:?}", e),
+        }
+        // Messages composed while offline.
+        scheduler.push(Command::SendOutbox, Priority::Interactive);
+        let result = Self::serve(
+            shared_state,
+            &tg_client,
+            command_receiver,
+            scheduler,
+            redraw_sender,
+        )
+        .await;
+        Self::save_update_state_logged(shared_state, &tg_client, scheduler).await;
+        result
+    }
+
+    async fn serve(
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        command_receiver: &mut Receiver<Command>,
+        scheduler: &mut RequestScheduler<Command>,
+        redraw_sender: &Sender<()>,
+    ) -> ServeResult {
+        let mut state_save_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + STATE_SAVE_INTERVAL,
+            STATE_SAVE_INTERVAL,
+        );
+        let mut catch_up_deadline = tokio::time::Instant::now() + CATCH_UP_QUIET_TIME;
         loop {
+            let updating =
+                shared_state.lock().unwrap().connection_state == ConnectionState::Updating;
+            let ready_time = scheduler.next_ready_time();
+            // Without ready time the branch is disabled, so the deadline
+            // is never waited for.
+            let command_deadline =
+                tokio::time::Instant::from_std(ready_time.unwrap_or_else(Instant::now));
             tokio::select! {
                 maybe_command = command_receiver.recv() => {
-                    if let Some(command) = maybe_command {
-                        if let Err(e) = Self::handle_command(&command, &shared_state, &tg_client).await {
-                            log::error!("Error during command {:?} handling {:?}", command, e);
-                        }
-                    } else {
-                        return;
+                    let Some(command) = maybe_command else {
+                        return ServeResult::Stopped;
+                    };
+                    let priority = command.priority();
+                    scheduler.push(command, priority);
+                },
+                _ = tokio::time::sleep_until(command_deadline), if ready_time.is_some() => {
+                    if let Some((command, priority)) = scheduler.pop_ready(Instant::now()) {
+                        Self::run_command(
+                            command,
+                            priority,
+                            shared_state,
+                            tg_client,
+                            scheduler,
+                            redraw_sender,
+                        )
+                        .await;
+                        Self::request_redraw(redraw_sender);
                     }
                 },
                 maybe_update = tg_client.next_update() => {
-                    if let Ok(update) = maybe_update {
-                        if let Err(e) = Self::handle_update(&shared_state, update).await {
-                            log::error!("Error during update handling {:?}", e);
+                    let update = match maybe_update {
+                        Ok(update) => update,
+                        Err(e) => {
+                            log::error!("Failed get update, reconnecting {:?}", e);
+                            return ServeResult::Disconnected;
                         }
-                    } else {
-                        log::error!("Failed get update {:?}", maybe_update);
+                    };
+                    if let Err(e) = Self::handle_update(shared_state, update, scheduler).await {
+                        log::error!("Error during update handling {:?}", e);
                     }
+                    Self::request_redraw(redraw_sender);
+                    if updating {
+                        catch_up_deadline = tokio::time::Instant::now() + CATCH_UP_QUIET_TIME;
+                    }
+                },
+                // State changes by commands too, e.g. sent messages.
+                _ = state_save_interval.tick() => {
+                    Self::save_update_state_logged(shared_state, tg_client, scheduler).await;
+                },
+                _ = tokio::time::sleep_until(catch_up_deadline), if updating => {
+                    Self::set_connection_state(
+                        shared_state,
+                        ConnectionState::Online,
+                        redraw_sender,
+                    );
+                }
+            }
+        }
+    }
+
+    // Makes a new client, as client, which connection failed, can't be
+    // used anymore. Update state of the previous client is restored, so
+    // updates missed meanwhile are fetched.
+    async fn connect(
+        shared_state: &Arc<Mutex<SharedState>>,
+        api_credentials: &ApiCredentials,
+    ) -> Result<Client> {
+        let session = {
+            let locked_state = shared_state.lock().unwrap();
+            let session = locked_state.storage.load_session()?;
+            if let Some(state) = locked_state.storage.load_update_state()? {
+                session.set_state(state);
+            }
+            session
+        };
+        tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            TgClientBuilder::connect(session, api_credentials),
+        )
+        .await
+        .map_err(|_| eyre!("Connection timed out"))?
+    }
+
+    // Waits for `future`, keeping commands, that come meanwhile, in
+    // `scheduler`. Returns None if runtime is stopped.
+    async fn wait_keeping_commands<F: Future>(
+        future: F,
+        command_receiver: &mut Receiver<Command>,
+        scheduler: &mut RequestScheduler<Command>,
+    ) -> Option<F::Output> {
+        tokio::pin!(future);
+        loop {
+            tokio::select! {
+                output = &mut future => return Some(output),
+                maybe_command = command_receiver.recv() => {
+                    let command = maybe_command?;
+                    let priority = command.priority();
+                    scheduler.push(command, priority);
+                }
+            }
+        }
+    }
+
+    // Goes offline for `reconnect_delay` and doubles it for the next time.
+    // Returns false if runtime is stopped.
+    async fn wait_reconnect(
+        reconnect_delay: &mut Duration,
+        shared_state: &Arc<Mutex<SharedState>>,
+        command_receiver: &mut Receiver<Command>,
+        scheduler: &mut RequestScheduler<Command>,
+        redraw_sender: &Sender<()>,
+    ) -> bool {
+        Self::set_connection_state(shared_state, ConnectionState::Offline, redraw_sender);
+        log::info!("Reconnecting in {:?}", reconnect_delay);
+        let delay = *reconnect_delay;
+        *reconnect_delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+        Self::wait_keeping_commands(tokio::time::sleep(delay), command_receiver, scheduler)
+            .await
+            .is_some()
+    }
+
+    fn set_connection_state(
+        shared_state: &Arc<Mutex<SharedState>>,
+        connection_state: ConnectionState,
+        redraw_sender: &Sender<()>,
+    ) {
+        log::info!("Connection state {:?}", connection_state);
+        shared_state.lock().unwrap().connection_state = connection_state;
+        Self::request_redraw(redraw_sender);
+    }
+
+    // Update state (pts, qts, seq and date of the account and pts of
+    // channels) is a part of the session, so after restart grammers fetches
+    // only the difference since it. grammers counts updates in the state,
+    // when they are queued, so queued updates are handled first, or they
+    // would be lost after restart.
+    async fn save_update_state(
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        scheduler: &mut RequestScheduler<Command>,
+    ) -> Result<()> {
+        // Queued updates are returned at once, otherwise grammers waits for
+        // the network, and the update is left for the next time.
+        while let Some(maybe_update) = tg_client.next_update().now_or_never() {
+            let update = match maybe_update {
+                Ok(update) => update,
+                Err(e) => {
+                    log::error!("Failed get queued update {:?}", e);
+                    break;
+                }
+            };
+            if let Err(e) = Self::handle_update(shared_state, update, scheduler).await {
+                log::error!("Error during update handling {:?}", e);
+            }
+        }
+        tg_client.sync_update_state();
+        let Some(state) = tg_client.session().get_state() else {
+            return Ok(());
+        };
+        let locked_state = shared_state.lock().unwrap();
+        if let Some(saved_state) = locked_state.storage.load_update_state()? {
+            if Self::has_channel_gap(&saved_state, &state) {
+                log::info!("Too many channel updates missed, refreshing dialogs");
+                scheduler.push(Command::RefreshDialogs, Priority::Background);
+            }
+        }
+        locked_state.storage.save_update_state(&state)?;
+        locked_state.storage.save_session(tg_client.session())
+    }
+
+    async fn save_update_state_logged(
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        scheduler: &mut RequestScheduler<Command>,
+    ) {
+        if let Err(e) = Self::save_update_state(shared_state, tg_client, scheduler).await {
+            log::error!("Failed save update state {:?}", e);
+        }
+    }
+
+    // Returns true if some channel got more updates since `saved_state`,
+    // than grammers fetches, so the rest were skipped, and its dialog must
+    // be fetched again.
+    fn has_channel_gap(saved_state: &UpdateState, state: &UpdateState) -> bool {
+        let saved_pts = storage::channel_pts(saved_state);
+        storage::channel_pts(state)
+            .into_iter()
+            .any(|(channel_id, pts)| {
+                saved_pts
+                    .get(&channel_id)
+                    .is_some_and(|saved_pts| pts - saved_pts > CHANNEL_DIFFERENCE_LIMIT)
+            })
+    }
+
+    // Failure means either that redraw is already pending, so many changes
+    // in a row cause one redraw, or that UI is closed already.
+    fn request_redraw(redraw_sender: &Sender<()>) {
+        let _ = redraw_sender.try_send(());
+    }
+
+    // Commands failed because of flood limits are put back to the
+    // scheduler, to be retried after the required wait.
+    async fn run_command(
+        command: Command,
+        priority: Priority,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        scheduler: &mut RequestScheduler<Command>,
+        redraw_sender: &Sender<()>,
+    ) {
+        let result = Self::handle_command(&command, shared_state, tg_client, redraw_sender).await;
+        match result {
+            Ok(Some(next_command)) => {
+                let next_priority = next_command.priority();
+                scheduler.push(next_command, next_priority);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                if let Some(wait) = scheduler::flood_wait(&e) {
+                    log::warn!("Flood wait {:?} for command {:?}", wait, command);
+                    scheduler.delay(command, priority, wait);
+                    return;
                 }
+                log::error!("Error during command {:?} handling {:?}", command, e);
             }
         }
+        Self::finish_command(&command, shared_state);
+    }
+
+    // Resets state, that marks command as being in progress.
+    fn finish_command(command: &Command, shared_state: &Arc<Mutex<SharedState>>) {
+        let mut locked_state = shared_state.lock().unwrap();
+        match command {
+            Command::LoadOlderMessages(..) => {
+                locked_state.pending_history_request = None;
+            }
+            Command::MarkAsRead(_) => {
+                locked_state.pending_read_chat = None;
+            }
+            _ => {}
+        }
     }
 
     async fn handle_command(
         command: &Command,
         shared_state: &Arc<Mutex<SharedState>>,
         tg_client: &Client,
-    ) -> Result<()> {
+        redraw_sender: &Sender<()>,
+    ) -> Result<Option<Command>> {
         match command {
+            Command::LoadDialogs(offset) => {
+                // Pages are fetched by separate commands, so the scheduler
+                // paces them, and flood wait retries only the failed one.
+                let next_offset =
+                    Self::load_dialogs_page(offset.as_ref(), shared_state, tg_client).await?;
+                return Ok(next_offset.map(|offset| Command::LoadDialogs(Some(offset))));
+            }
+            Command::RefreshDialogs => {
+                Self::load_dialogs_page(None, shared_state, tg_client).await?;
+            }
+            Command::CheckMissedUpdates { pts } => {
+                return Self::check_missed_updates(*pts, tg_client).await;
+            }
             Command::RefreshMessages(chat) => {
                 Self::refresh_messages(chat, shared_state, tg_client).await?;
             }
+            Command::LoadOlderMessages(chat, offset_id) => {
+                Self::load_older_messages_impl(chat, *offset_id, shared_state, tg_client).await?;
+            }
+            Command::SendOutbox => {
+                Self::send_outbox(shared_state, tg_client).await?;
+            }
+            Command::Forward {
+                from,
+                message_id,
+                to,
+            } => {
+                let messages = tg_client
+                    .forward_messages(*to, &[*message_id], *from)
+                    .await?;
+                let locked_state = shared_state.lock().unwrap();
+                for message in messages.into_iter().flatten() {
+                    locked_state.storage.save_message(&message)?;
+                    locked_state
+                        .storage
+                        .extend_newest_history_range(storage::chat_peer_id(to), message.id())?;
+                    locked_state
+                        .storage
+                        .add_message_to_dialog(&message, false)?;
+                }
+            }
+            Command::Edit {
+                chat,
+                message_id,
+                text,
+            } => {
+                tg_client
+                    .edit_message(chat, *message_id, text.as_str())
+                    .await?;
+                let edit_date = chrono::Utc::now().timestamp() as i32;
+                let locked_state = shared_state.lock().unwrap();
+                locked_state.storage.edit_message_text(
+                    storage::chat_peer_id(&chat.pack()),
+                    *message_id,
+                    text,
+                    edit_date,
+                )?;
+            }
+            Command::Delete {
+                chat,
+                message_id,
+                revoke,
+            } => {
+                Self::delete_message_impl(chat, *message_id, *revoke, shared_state, tg_client)
+                    .await?;
+            }
+            Command::MarkAsRead(chat) => {
+                tg_client.mark_as_read(chat).await?;
+                let locked_state = shared_state.lock().unwrap();
+                locked_state
+                    .storage
+                    .update_dialog(&chat.pack().to_peer(), |dialog| {
+                        dialog.read_inbox_max_id =
+                            std::cmp::max(dialog.read_inbox_max_id, dialog.top_message);
+                        dialog.unread_count = 0;
+                        dialog.unread_mentions_count = 0;
+                    })?;
+            }
+            Command::Download {
+                media,
+                chat,
+                message_id,
+            } => {
+                // Downloads run in parallel with other commands, so long ones
+                // don't block the chat.
+                tokio::spawn(Self::download_media_task(
+                    *media.clone(),
+                    (*chat, *message_id),
+                    shared_state.clone(),
+                    tg_client.clone(),
+                    redraw_sender.clone(),
+                ));
+            }
+            Command::SendFile(upload_id) => {
+                let mut locked_state = shared_state.lock().unwrap();
+                // Upload may be cancelled while the command was queued.
+                if let Some(upload) = locked_state
+                    .uploads
+                    .iter_mut()
+                    .find(|upload| upload.id == *upload_id)
+                {
+                    let handle = tokio::spawn(Self::upload_file_task(
+                        *upload_id,
+                        shared_state.clone(),
+                        tg_client.clone(),
+                        redraw_sender.clone(),
+                    ));
+                    upload.abort_handle = Some(handle.abort_handle());
+                }
+            }
+            Command::Search {
+                generation,
+                text,
+                chat,
+            } => {
+                // Search fetches many pages, so it runs in parallel, not to
+                // delay updates and other commands.
+                let handle = tokio::spawn(Self::search_task(
+                    *generation,
+                    SearchResults::new(tg_client, text, chat.as_ref()),
+                    shared_state.clone(),
+                    redraw_sender.clone(),
+                ));
+                let mut locked_state = shared_state.lock().unwrap();
+                if locked_state.server_search.generation == *generation {
+                    locked_state.server_search.abort_handle = Some(handle.abort_handle());
+                } else {
+                    handle.abort();
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn upload_file_task(
+        upload_id: u64,
+        shared_state: Arc<Mutex<SharedState>>,
+        tg_client: Client,
+        redraw_sender: Sender<()>,
+    ) {
+        let result = Self::upload_file(upload_id, &shared_state, &tg_client, &redraw_sender).await;
+        let mut locked_state = shared_state.lock().unwrap();
+        match result {
+            Ok(()) => locked_state.uploads.retain(|upload| upload.id != upload_id),
+            Err(e) => {
+                log::error!("Failed send file; Error {:?}", e);
+                if let Some(upload) = locked_state
+                    .uploads
+                    .iter_mut()
+                    .find(|upload| upload.id == upload_id)
+                {
+                    upload.failed = true;
+                    upload.abort_handle = None;
+                }
+            }
+        }
+        drop(locked_state);
+        Self::request_redraw(&redraw_sender);
+    }
+
+    async fn upload_file(
+        upload_id: u64,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        redraw_sender: &Sender<()>,
+    ) -> Result<()> {
+        let (chat, path, kind, caption, uploaded) = {
+            let locked_state = shared_state.lock().unwrap();
+            let Some(upload) = locked_state
+                .uploads
+                .iter()
+                .find(|upload| upload.id == upload_id)
+            else {
+                return Ok(());
+            };
+            (
+                upload.chat,
+                upload.path.clone(),
+                upload.kind,
+                upload.caption.clone(),
+                upload.uploaded.clone(),
+            )
+        };
+        let file = tokio::fs::File::open(&path).await?;
+        let size = file.metadata().await?.len();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        uploaded.store(0, Ordering::Relaxed);
+        let mut reader = ProgressReader {
+            inner: file,
+            read: uploaded,
+            redraw_sender: redraw_sender.clone(),
+        };
+        let uploaded_file = tg_client
+            .upload_stream(&mut reader, usize::try_from(size)?, file_name)
+            .await?;
+        let input_message = InputMessage::text(&caption);
+        let input_message = match kind {
+            FileKind::Document => input_message.document(uploaded_file),
+            FileKind::Photo => input_message.photo(uploaded_file),
+            // Duration is unknown, clients show audio without it.
+            FileKind::Audio => input_message
+                .document(uploaded_file)
+                .attribute(Attribute::Audio {
+                    duration: Duration::ZERO,
+                    title: None,
+                    performer: None,
+                }),
+        };
+        let message = tg_client.send_message(chat, input_message).await?;
+        Self::save_sent_message(&message, storage::chat_peer_id(&chat), shared_state)
+    }
+
+    async fn download_media_task(
+        media: Media,
+        message: (PackedChat, i32),
+        shared_state: Arc<Mutex<SharedState>>,
+        tg_client: Client,
+        redraw_sender: Sender<()>,
+    ) {
+        let Some(media_key) = media_cache::media_key(&media) else {
+            return;
+        };
+        let result = Self::download_to_cache(
+            media,
+            message,
+            &media_key,
+            &shared_state,
+            &tg_client,
+            &redraw_sender,
+        )
+        .await;
+        let state = match result {
+            Ok(path) => DownloadState::Done(path),
+            Err(e) => {
+                log::error!("Failed download media {}; Error {:?}", media_key, e);
+                DownloadState::Failed
+            }
+        };
+        Self::update_download(&shared_state, &media_key, |progress| progress.state = state);
+        Self::request_redraw(&redraw_sender);
+    }
+
+    // Returns path of the downloaded file. `message` is chat and ID of
+    // the message with the media.
+    async fn download_to_cache(
+        mut media: Media,
+        message: (PackedChat, i32),
+        media_key: &str,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+        redraw_sender: &Sender<()>,
+    ) -> Result<PathBuf> {
+        let (cache, download_slots) = {
+            let locked_state = shared_state.lock().unwrap();
+            (
+                locked_state.media_cache.clone(),
+                locked_state.download_slots.clone(),
+            )
+        };
+        let _slot = download_slots.acquire().await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(cache.partial_path(media_key))
+            .await?;
+        // The last chunk may be incomplete, if the app was stopped while
+        // writing it.
+        let done_chunks = file.metadata().await?.len() / DOWNLOAD_CHUNK_SIZE as u64;
+        let mut downloaded = done_chunks * DOWNLOAD_CHUNK_SIZE as u64;
+        file.set_len(downloaded).await?;
+        file.seek(SeekFrom::End(0)).await?;
+        let mut download = Self::iter_download(&media, downloaded, tg_client)?;
+        let mut reference_refreshed = false;
+        loop {
+            Self::update_download(shared_state, media_key, |progress| {
+                progress.state = DownloadState::Downloading;
+                progress.downloaded = downloaded;
+            });
+            Self::request_redraw(redraw_sender);
+            let chunk = match download.next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                // File reference of the stored message expires in hours.
+                Err(e) if e.is("FILE_REFERENCE_*") && !reference_refreshed => {
+                    log::info!("File reference of {} expired, refreshing", media_key);
+                    media = Self::refresh_media(message, shared_state, tg_client).await?;
+                    download = Self::iter_download(&media, downloaded, tg_client)?;
+                    reference_refreshed = true;
+                    continue;
+                }
+                Err(e) => {
+                    let e = eyre::Report::from(e);
+                    let Some(wait) = scheduler::flood_wait(&e) else {
+                        return Err(e);
+                    };
+                    // Iterator keeps offset of the failed chunk.
+                    log::warn!("Flood wait {:?} for download of {}", wait, media_key);
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
+            };
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+        }
+        file.flush().await?;
+        drop(file);
+        // Hashing of big files takes long, don't block the runtime with it.
+        let file_name = media_cache::media_file_name(&media);
+        let media_file = {
+            let cache = cache.clone();
+            let media_key = media_key.to_string();
+            tokio::task::spawn_blocking(move || cache.add_downloaded(&media_key, &file_name))
+                .await??
+        };
+        let locked_state = shared_state.lock().unwrap();
+        locked_state
+            .storage
+            .save_media_file(media_key, &media_file)?;
+        Ok(cache.file_path(&media_file))
+    }
+
+    // Returns iterator over chunks after the `downloaded` bytes, which
+    // are whole chunks.
+    fn iter_download(media: &Media, downloaded: u64, tg_client: &Client) -> Result<DownloadIter> {
+        let done_chunks = downloaded / DOWNLOAD_CHUNK_SIZE as u64;
+        Ok(tg_client
+            .iter_download(&Downloadable::Media(media.clone()))
+            .chunk_size(DOWNLOAD_CHUNK_SIZE)
+            .skip_chunks(i32::try_from(done_chunks)?))
+    }
+
+    // Fetches the message again, as its media has fresh file reference,
+    // and stores it, so later downloads use the reference too.
+    async fn refresh_media(
+        (chat, message_id): (PackedChat, i32),
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+    ) -> Result<Media> {
+        let message = tg_client
+            .get_messages_by_id(chat, &[message_id])
+            .await?
+            .into_iter()
+            .flatten()
+            .next()
+            .ok_or_else(|| eyre!("Message {} with media is deleted", message_id))?;
+        shared_state
+            .lock()
+            .unwrap()
+            .storage
+            .save_message(&message)?;
+        message
+            .media()
+            .ok_or_else(|| eyre!("Message {} has no media anymore", message_id))
+    }
+
+    fn update_download<F>(shared_state: &Arc<Mutex<SharedState>>, media_key: &str, modify: F)
+    where
+        F: FnOnce(&mut DownloadProgress),
+    {
+        let mut locked_state = shared_state.lock().unwrap();
+        if let Some(progress) = locked_state.downloads.get_mut(media_key) {
+            modify(progress);
         }
-        Ok(())
     }
 
     async fn refresh_messages(
@@ -101,18 +1153,323 @@ impl Runtime {
         while let Some(msg) = messages.next().await? {
             all_messages.push(msg);
         }
+        if let Some(newest) = all_messages.first() {
+            let last_id = newest.id();
+            Self::save_history_portion(chat, all_messages, last_id, shared_state)?;
+        }
+        Ok(())
+    }
+
+    async fn load_older_messages_impl(
+        chat: &Chat,
+        offset_id: i32,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+    ) -> Result<()> {
+        let mut messages = tg_client
+            .iter_messages(chat)
+            .offset_id(offset_id)
+            .limit(MESSAGES_PORTION_SIZE);
+        let mut all_messages = Vec::new();
+        while let Some(msg) = messages.next().await? {
+            all_messages.push(msg);
+        }
+        Self::save_history_portion(chat, all_messages, offset_id - 1, shared_state)
+    }
+
+    // Saves messages, fetched from server newest first, and marks
+    // all IDs from the oldest of them up to `last_id` as loaded.
+    fn save_history_portion(
+        chat: &Chat,
+        messages: Vec<Message>,
+        last_id: i32,
+        shared_state: &Arc<Mutex<SharedState>>,
+    ) -> Result<()> {
+        // Server returned less than requested, so the history start reached.
+        let first_id = match messages.last() {
+            Some(oldest) if messages.len() == MESSAGES_PORTION_SIZE => oldest.id(),
+            _ => 0,
+        };
         let locked_state = shared_state.lock().unwrap();
-        for message in all_messages {
+        for message in messages {
             locked_state.storage.save_message(&message)?;
         }
+        locked_state.storage.add_history_range(
+            storage::chat_peer_id(&chat.pack()),
+            first_id,
+            last_id,
+        )?;
+        Ok(())
+    }
+
+    async fn send_outbox(shared_state: &Arc<Mutex<SharedState>>, tg_client: &Client) -> Result<()> {
+        let messages = shared_state
+            .lock()
+            .unwrap()
+            .storage
+            .select_pending_outbox_messages()?;
+        for message in messages {
+            match Self::send_outbox_message(&message, shared_state, tg_client).await {
+                Ok(()) => {}
+                // Left pending, to be sent when the wait is over.
+                Err(e) if scheduler::flood_wait(&e).is_some() => return Err(e),
+                // Server rejected this message only, others may be sent.
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<InvocationError>(),
+                        Some(InvocationError::Rpc(_))
+                    ) =>
+                {
+                    log::error!("Failed send message {} {:?}", message.local_id, e);
+                    let locked_state = shared_state.lock().unwrap();
+                    locked_state
+                        .storage
+                        .set_outbox_state(message.local_id, OutboxState::Failed)?;
+                }
+                // Connection failed, message is left pending to be sent
+                // after reconnect. If it was delivered meanwhile, server
+                // recognizes its random ID, so it is not duplicated.
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_outbox_message(
+        outbox_message: &OutboxMessage,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+    ) -> Result<()> {
+        shared_state
+            .lock()
+            .unwrap()
+            .storage
+            .set_outbox_in_flight(outbox_message.local_id)?;
+        // Sent with the stored random ID, so the message is not duplicated,
+        // if it was delivered before, but the response was lost.
+        let request = tl_types::functions::messages::SendMessage {
+            no_webpage: true,
+            silent: false,
+            background: false,
+            clear_draft: false,
+            noforwards: false,
+            update_stickersets_order: false,
+            invert_media: false,
+            peer: outbox_message.chat.to_input_peer(),
+            reply_to: outbox_message.reply_to.map(|reply_to_msg_id| {
+                tl_types::types::InputReplyToMessage {
+                    reply_to_msg_id,
+                    top_msg_id: None,
+                    reply_to_peer_id: None,
+                    quote_text: None,
+                    quote_entities: None,
+                    quote_offset: None,
+                }
+                .into()
+            }),
+            message: outbox_message.text.clone(),
+            random_id: outbox_message.random_id,
+            reply_markup: None,
+            entities: None,
+            schedule_date: None,
+            send_as: None,
+            quick_reply_shortcut: None,
+            effect: None,
+        };
+        let message = match tg_client.invoke(&request).await {
+            Ok(tl_types::enums::Updates::UpdateShortSentMessage(updates)) => {
+                let input_message =
+                    InputMessage::text(&outbox_message.text).reply_to(outbox_message.reply_to);
+                Some(Message::from_raw_short_updates(
+                    tg_client,
+                    updates,
+                    input_message,
+                    outbox_message.chat,
+                ))
+            }
+            Ok(updates) => Self::find_sent_message(tg_client, updates, outbox_message.random_id),
+            // Delivered before, the message itself comes with updates.
+            Err(e) if e.is("RANDOM_ID_DUPLICATE") => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(message) = message {
+            Self::save_sent_message(
+                &message,
+                storage::chat_peer_id(&outbox_message.chat),
+                shared_state,
+            )?;
+        }
+        let locked_state = shared_state.lock().unwrap();
+        locked_state
+            .storage
+            .delete_outbox_message(outbox_message.local_id)?;
+        Ok(())
+    }
+
+    // Finds message, that server created for the request with `random_id`.
+    fn find_sent_message(
+        tg_client: &Client,
+        updates: tl_types::enums::Updates,
+        random_id: i64,
+    ) -> Option<Message> {
+        let (updates, users, chats) = match updates {
+            tl_types::enums::Updates::Updates(u) => (u.updates, u.users, u.chats),
+            tl_types::enums::Updates::Combined(u) => (u.updates, u.users, u.chats),
+            _ => return None,
+        };
+        let message_id = updates.iter().find_map(|update| match update {
+            tl_types::enums::Update::MessageId(u) if u.random_id == random_id => Some(u.id),
+            _ => None,
+        })?;
+        let chats = ChatMap::new(users, chats);
+        updates
+            .into_iter()
+            .filter_map(|update| match update {
+                tl_types::enums::Update::NewMessage(u) => Some(u.message),
+                tl_types::enums::Update::NewChannelMessage(u) => Some(u.message),
+                _ => None,
+            })
+            .filter_map(|raw| Message::from_raw(tg_client, raw, &chats))
+            .find(|message| message.id() == message_id)
+    }
+
+    // Don't wait for the update, so message appears in the view at once.
+    fn save_sent_message(
+        message: &Message,
+        peer_id: i64,
+        shared_state: &Arc<Mutex<SharedState>>,
+    ) -> Result<()> {
+        let mut locked_state = shared_state.lock().unwrap();
+        locked_state.storage.save_message(message)?;
+        locked_state.remember_message_date(message);
+        locked_state
+            .storage
+            .extend_newest_history_range(peer_id, message.id())?;
+        locked_state.storage.add_message_to_dialog(message, false)
+    }
+
+    async fn delete_message_impl(
+        chat: &Chat,
+        message_id: i32,
+        revoke: bool,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+    ) -> Result<()> {
+        let channel_id = match chat.pack().to_peer() {
+            tl_types::enums::Peer::Channel(channel) => Some(channel.channel_id),
+            _ => None,
+        };
+        // Messages of channels and megagroups are always deleted for all.
+        if revoke || channel_id.is_some() {
+            tg_client.delete_messages(chat, &[message_id]).await?;
+        } else {
+            tg_client
+                .invoke(&tl_types::functions::messages::DeleteMessages {
+                    revoke: false,
+                    id: vec![message_id],
+                })
+                .await?;
+        }
+        let locked_state = shared_state.lock().unwrap();
+        locked_state
+            .storage
+            .delete_messages(channel_id, &[message_id])
+    }
+
+    async fn search_task(
+        generation: u64,
+        mut messages: SearchResults,
+        shared_state: Arc<Mutex<SharedState>>,
+        redraw_sender: Sender<()>,
+    ) {
+        if let Err(e) =
+            Self::search_on_server(generation, &mut messages, &shared_state, &redraw_sender).await
+        {
+            log::error!("Failed search messages on server; Error {:?}", e);
+        }
+        let mut locked_state = shared_state.lock().unwrap();
+        if locked_state.server_search.generation == generation {
+            locked_state.server_search.in_progress = false;
+            locked_state.server_search.abort_handle = None;
+        }
+        drop(locked_state);
+        Self::request_redraw(&redraw_sender);
+    }
+
+    async fn search_on_server(
+        generation: u64,
+        messages: &mut SearchResults,
+        shared_state: &Arc<Mutex<SharedState>>,
+        redraw_sender: &Sender<()>,
+    ) -> Result<()> {
+        loop {
+            let message = match messages.next().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    let e = eyre::Report::from(e);
+                    let Some(wait) = scheduler::flood_wait(&e) else {
+                        return Err(e);
+                    };
+                    // Iterator keeps offset of the failed page, so search
+                    // resumes with it, not repeating found messages.
+                    log::warn!("Flood wait {:?} for server search", wait);
+                    tokio::time::sleep(wait).await;
+                    continue;
+                }
+            };
+            if !Self::add_server_search_result(generation, message, shared_state)? {
+                break;
+            }
+            Self::request_redraw(redraw_sender);
+        }
         Ok(())
     }
 
-    async fn handle_update(shared_state: &Arc<Mutex<SharedState>>, update: Update) -> Result<()> {
+    // Returns false if the search is outdated and should be stopped.
+    fn add_server_search_result(
+        generation: u64,
+        message: Message,
+        shared_state: &Arc<Mutex<SharedState>>,
+    ) -> Result<bool> {
+        let mut locked_state = shared_state.lock().unwrap();
+        if locked_state.server_search.generation != generation {
+            return Ok(false);
+        }
+        locked_state.storage.save_message(&message)?;
+        locked_state
+            .server_search
+            .results
+            .push(StoredMessage::from_message(&message));
+        Ok(true)
+    }
+
+    async fn handle_update(
+        shared_state: &Arc<Mutex<SharedState>>,
+        update: Update,
+        scheduler: &mut RequestScheduler<Command>,
+    ) -> Result<()> {
         match update {
             Update::NewMessage(message) => {
-                let locked_state = shared_state.lock().unwrap();
+                let mut locked_state = shared_state.lock().unwrap();
                 locked_state.storage.save_message(&message)?;
+                locked_state.remember_message_date(&message);
+                locked_state.storage.extend_newest_history_range(
+                    storage::chat_peer_id(&message.chat().pack()),
+                    message.id(),
+                )?;
+                // User sees messages of the open chat as they arrive.
+                let chat = message.chat();
+                let in_active_chat = locked_state
+                    .active_chat
+                    .as_ref()
+                    .is_some_and(|active_chat| active_chat.pack() == chat.pack());
+                let read = in_active_chat && !message.outgoing();
+                locked_state.storage.add_message_to_dialog(&message, read)?;
+                if read && locked_state.pending_read_chat != Some(chat.id()) {
+                    locked_state.pending_read_chat = Some(chat.id());
+                    scheduler.push(Command::MarkAsRead(chat), Priority::Interactive);
+                }
             }
             Update::MessageEdited(message) => {
                 let locked_state = shared_state.lock().unwrap();
@@ -122,6 +1479,10 @@ impl Runtime {
                 let locked_state = shared_state.lock().unwrap();
                 locked_state.storage.delete_message(&message_deletion)?;
             }
+            Update::Raw(raw_update) => {
+                let locked_state = shared_state.lock().unwrap();
+                Self::handle_raw_update(&locked_state.storage, raw_update)?;
+            }
             _ => {
                 log::info!("Not handled yet update {:?}", update);
             }
@@ -129,21 +1490,180 @@ impl Runtime {
         Ok(())
     }
 
-    async fn do_initial_update(
-        shared_state: &Arc<Mutex<SharedState>>,
-        tg_client: &Client,
+    // Keeps stored dialogs in sync with changes, made on other devices.
+    fn handle_raw_update(
+        storage: &storage::Storage,
+        update: tl_types::enums::Update,
     ) -> Result<()> {
-        let mut retrieved_dialogs = Vec::new();
-        let mut it = tg_client.iter_dialogs();
-        while let Some(dialog) = it.next().await? {
-            retrieved_dialogs.push(dialog);
+        match update {
+            // Confirms message, sent by this app, when response to the
+            // sending request is lost.
+            tl_types::enums::Update::MessageId(sent) => {
+                if storage.reconcile_outbox(sent.random_id)? {
+                    log::info!("Outbox message reconciled with {}", sent.id);
+                }
+            }
+            tl_types::enums::Update::ReadHistoryInbox(read) => {
+                storage.update_dialog(&read.peer, |dialog| {
+                    dialog.read_inbox_max_id = read.max_id;
+                    dialog.unread_count = read.still_unread_count;
+                })?;
+            }
+            tl_types::enums::Update::ReadChannelInbox(read) => {
+                let peer = tl_types::types::PeerChannel {
+                    channel_id: read.channel_id,
+                }
+                .into();
+                storage.update_dialog(&peer, |dialog| {
+                    dialog.read_inbox_max_id = read.max_id;
+                    dialog.unread_count = read.still_unread_count;
+                })?;
+            }
+            tl_types::enums::Update::ReadHistoryOutbox(read) => {
+                storage.update_dialog(&read.peer, |dialog| {
+                    dialog.read_outbox_max_id = read.max_id;
+                })?;
+            }
+            tl_types::enums::Update::ReadChannelOutbox(read) => {
+                let peer = tl_types::types::PeerChannel {
+                    channel_id: read.channel_id,
+                }
+                .into();
+                storage.update_dialog(&peer, |dialog| {
+                    dialog.read_outbox_max_id = read.max_id;
+                })?;
+            }
+            tl_types::enums::Update::DialogPinned(pinned) => {
+                if let tl_types::enums::DialogPeer::Peer(dialog_peer) = &pinned.peer {
+                    storage.update_dialog(&dialog_peer.peer, |dialog| {
+                        dialog.pinned = pinned.pinned;
+                    })?;
+                }
+            }
+            tl_types::enums::Update::PinnedDialogs(pinned) => {
+                // Without order client must re-fetch pinned dialogs, they
+                // are updated on the next dialogs fetch then.
+                if let Some(order) = pinned.order {
+                    let pinned_peers: Vec<tl_types::enums::Peer> = order
+                        .into_iter()
+                        .filter_map(|dialog_peer| match dialog_peer {
+                            tl_types::enums::DialogPeer::Peer(p) => Some(p.peer),
+                            tl_types::enums::DialogPeer::Folder(_) => None,
+                        })
+                        .collect();
+                    storage.update_all_dialogs(|dialog| {
+                        if dialog.folder_id == pinned.folder_id {
+                            dialog.pinned = pinned_peers.contains(&dialog.peer);
+                        }
+                    })?;
+                }
+            }
+            tl_types::enums::Update::FolderPeers(folder_peers) => {
+                for tl_types::enums::FolderPeer::Peer(folder_peer) in folder_peers.folder_peers {
+                    // Zero is ID of the main list, stored as no folder.
+                    let folder_id = (folder_peer.folder_id != 0).then_some(folder_peer.folder_id);
+                    storage.update_dialog(&folder_peer.peer, |dialog| {
+                        dialog.folder_id = folder_id;
+                    })?;
+                }
+            }
+            _ => {
+                log::info!("Not handled yet update {:?}", update);
+            }
         }
+        Ok(())
+    }
+
+    fn load_update_state(shared_state: &Arc<Mutex<SharedState>>) -> Result<Option<UpdateState>> {
+        let locked_state = shared_state.lock().unwrap();
+        locked_state.storage.load_update_state()
+    }
 
-        let i = shared_state.lock().unwrap();
-        for dialog in retrieved_dialogs {
-            i.storage.save_dialog(&dialog)?
+    // Returns LoadDialogs if server may not return updates missed since
+    // `pts`, as there are too many of them. Only the current state is
+    // fetched, grammers fetches the updates themselves.
+    async fn check_missed_updates(pts: i32, tg_client: &Client) -> Result<Option<Command>> {
+        let tl_types::enums::updates::State::State(state) = tg_client
+            .invoke(&tl_types::functions::updates::GetState {})
+            .await?;
+        if state.pts - pts > MAX_CAUGHT_UP_PTS {
+            log::info!("Too many updates missed, reloading dialogs");
+            return Ok(Some(Command::LoadDialogs(None)));
         }
-        Ok(())
+        Ok(None)
+    }
+
+    // Saves page of dialogs after the offset, with their top messages.
+    // Returns offset of the next page, or None if it was the last one.
+    async fn load_dialogs_page(
+        offset: Option<&DialogsOffset>,
+        shared_state: &Arc<Mutex<SharedState>>,
+        tg_client: &Client,
+    ) -> Result<Option<DialogsOffset>> {
+        let request = tl_types::functions::messages::GetDialogs {
+            // Pinned dialogs come in the first page regardless of offset.
+            exclude_pinned: offset.is_some(),
+            folder_id: None,
+            offset_date: offset.map_or(0, |offset| offset.date),
+            offset_id: offset.map_or(0, |offset| offset.message_id),
+            offset_peer: offset.map_or(tl_types::enums::InputPeer::Empty, |offset| {
+                offset.peer.to_input_peer()
+            }),
+            limit: DIALOGS_PAGE_SIZE as i32,
+            hash: 0,
+        };
+        let (dialogs, messages, chats, last_page) = match tg_client.invoke(&request).await? {
+            tl_types::enums::messages::Dialogs::Dialogs(d) => {
+                (d.dialogs, d.messages, ChatMap::new(d.users, d.chats), true)
+            }
+            tl_types::enums::messages::Dialogs::Slice(d) => {
+                let last_page = d.dialogs.len() < DIALOGS_PAGE_SIZE;
+                (
+                    d.dialogs,
+                    d.messages,
+                    ChatMap::new(d.users, d.chats),
+                    last_page,
+                )
+            }
+            // Returned only for requests with hash.
+            tl_types::enums::messages::Dialogs::NotModified(_) => return Ok(None),
+        };
+        let messages: Vec<Message> = messages
+            .into_iter()
+            .filter_map(|message| Message::from_raw(tg_client, message, &chats))
+            .collect();
+        let mut locked_state = shared_state.lock().unwrap();
+        // Top messages give dates, dialogs are sorted by.
+        for message in &messages {
+            locked_state.storage.save_message(message)?;
+            locked_state.remember_message_date(message);
+        }
+        let mut next_offset = None;
+        for raw in dialogs {
+            // Folders, e.g. archive, are not shown.
+            let tl_types::enums::Dialog::Dialog(dialog) = &raw else {
+                continue;
+            };
+            let Some(chat) = chats.get(&dialog.peer) else {
+                log::warn!("Dialog without chat {:?}", dialog.peer);
+                continue;
+            };
+            if let Some(top_message) = messages.iter().find(|message| {
+                message.raw.peer_id == dialog.peer && message.id() == dialog.top_message
+            }) {
+                next_offset = Some(DialogsOffset {
+                    date: top_message.raw.date,
+                    message_id: top_message.id(),
+                    peer: chat.pack(),
+                });
+            }
+            locked_state.storage.save_dialog(&Dialog {
+                raw: raw.clone(),
+                chat: chat.clone(),
+                last_message: None,
+            })?;
+        }
+        Ok(if last_page { None } else { next_offset })
     }
 
     pub fn get_dialogs(&self) -> Result<Vec<Dialog>> {
@@ -151,15 +1671,485 @@ impl Runtime {
         i.storage.select_all_dialogs()
     }
 
-    pub async fn start_message_refreshing(&self, chat: Chat) -> Result<()> {
+    /// Returns date of the last message of the dialog, if it is known.
+    pub fn get_last_message_date(&self, dialog: &Dialog) -> Result<Option<DateTime<Utc>>> {
+        let tl_types::enums::Dialog::Dialog(raw) = &dialog.raw else {
+            return Ok(None);
+        };
+        let key = (storage::peer_id(&raw.peer), raw.top_message);
+        let mut i = self.shared_state.lock().unwrap();
+        if let Some(date) = i.message_dates.get(&key) {
+            return Ok(Some(*date));
+        }
+        // Not cached messages are stored before this run, or loaded with
+        // the history.
+        let date = i.storage.select_top_message_date(raw)?;
+        if let Some(date) = date {
+            i.message_dates.insert(key, date);
+        }
+        Ok(date)
+    }
+
+    /// Returns total number of unread messages in all dialogs.
+    pub fn get_unread_count(&self) -> Result<i32> {
+        let count = self
+            .get_dialogs()?
+            .iter()
+            .map(|dialog| match &dialog.raw {
+                tl_types::enums::Dialog::Dialog(raw) => raw.unread_count,
+                tl_types::enums::Dialog::Folder(_) => 0,
+            })
+            .sum();
+        Ok(count)
+    }
+
+    /// Returns up to `limit` newest messages of the active chat,
+    /// newest first. Only messages without gaps between them are returned.
+    /// If the chat has an anchor, returns messages around it instead.
+    pub fn get_active_chat_messages(&self, limit: usize) -> Result<Vec<StoredMessage>> {
+        let i = self.shared_state.lock().unwrap();
+        let Some(chat) = i.active_chat.as_ref() else {
+            return Ok(Vec::new());
+        };
+        if let Some(anchor) = i.active_chat_anchor {
+            let newer_query = storage::MessagesQuery {
+                peer_id: storage::chat_peer_id(&chat.pack()),
+                range: storage::MessagesRange::After(anchor),
+                order: storage::MessagesOrder::IdAscending,
+                limit: MESSAGES_AFTER_ANCHOR,
+            };
+            let older_query = storage::MessagesQuery {
+                peer_id: storage::chat_peer_id(&chat.pack()),
+                range: storage::MessagesRange::Before(anchor + 1),
+                order: storage::MessagesOrder::IdDescending,
+                limit,
+            };
+            let mut result = i.storage.select_messages(&newer_query)?;
+            result.reverse();
+            result.extend(i.storage.select_messages(&older_query)?);
+            return Ok(result);
+        }
+        let range = match i
+            .storage
+            .newest_history_range(storage::chat_peer_id(&chat.pack()))?
+        {
+            // Message with first_id is loaded too.
+            Some((first_id, _)) => storage::MessagesRange::After(first_id - 1),
+            None => storage::MessagesRange::All,
+        };
+        let query = storage::MessagesQuery {
+            peer_id: storage::chat_peer_id(&chat.pack()),
+            range,
+            order: storage::MessagesOrder::IdDescending,
+            limit,
+        };
+        i.storage.select_messages(&query)
+    }
+
+    pub fn get_active_chat_anchor(&self) -> Option<i32> {
+        let i = self.shared_state.lock().unwrap();
+        i.active_chat_anchor
+    }
+
+    /// Returns to showing the newest messages of the active chat.
+    pub fn clear_active_chat_anchor(&self) {
+        let mut i = self.shared_state.lock().unwrap();
+        i.active_chat_anchor = None;
+    }
+
+    /// Requests next portion of the active chat history, preceding
+    /// messages returned by `get_active_chat_messages`.
+    pub fn load_older_messages(&self) -> Result<()> {
+        let mut i = self.shared_state.lock().unwrap();
+        let Some(chat) = i.active_chat.clone() else {
+            return Ok(());
+        };
+        if i.active_chat_anchor.is_some() {
+            // Messages around anchor are shown only from the local cache.
+            return Ok(());
+        }
+        let Some((first_id, _)) = i
+            .storage
+            .newest_history_range(storage::chat_peer_id(&chat.pack()))?
+        else {
+            // Newest messages are not loaded yet, RefreshMessages will do it.
+            return Ok(());
+        };
+        if first_id == 0 {
+            // Beginning of the history already loaded.
+            return Ok(());
+        }
+        let request = (chat.id(), first_id);
+        if i.pending_history_request == Some(request) {
+            return Ok(());
+        }
         self.command_sender
-            .send(Command::RefreshMessages(chat))
-            .await?;
+            .try_send(Command::LoadOlderMessages(chat, first_id))?;
+        i.pending_history_request = Some(request);
         Ok(())
     }
 
-    pub fn set_active_dialog(&self, chat_id: i64) {
+    pub fn get_active_chat(&self) -> Option<Chat> {
+        let i = self.shared_state.lock().unwrap();
+        i.active_chat.clone()
+    }
+
+    /// Returns marked ID of the active chat, which, unlike chat ID, differs
+    /// for users, groups and channels.
+    pub fn get_active_chat_id(&self) -> Option<i64> {
+        let i = self.shared_state.lock().unwrap();
+        i.active_chat
+            .as_ref()
+            .map(|chat| storage::chat_peer_id(&chat.pack()))
+    }
+
+    /// Makes chat with `chat_id` active and requests refreshing of its
+    /// messages from the server.
+    pub fn set_active_dialog(&self, chat_id: i64) -> Result<()> {
         log::info!("Activating dialog {}", chat_id);
+        let chat = {
+            let i = self.shared_state.lock().unwrap();
+            i.storage
+                .select_all_dialogs()?
+                .into_iter()
+                .map(|dialog| dialog.chat)
+                .find(|chat| chat.id() == chat_id)
+                .ok_or_else(|| eyre!("No dialog with chat ID {}", chat_id))?
+        };
+        self.activate_chat(chat, None)
+    }
+
+    /// Makes chat active and shows its messages around `message_id`.
+    pub fn jump_to_message(&self, chat: Chat, message_id: i32) -> Result<()> {
+        log::info!("Jumping to message {} in chat {}", message_id, chat.id());
+        self.activate_chat(chat, Some(message_id))
+    }
+
+    fn activate_chat(&self, chat: Chat, anchor: Option<i32>) -> Result<()> {
+        {
+            let mut i = self.shared_state.lock().unwrap();
+            let dialog = i.storage.select_raw_dialog(&chat.pack().to_peer())?;
+            i.active_chat = Some(chat.clone());
+            i.active_chat_anchor = anchor;
+            i.active_chat_read_inbox_max_id = dialog.map_or(0, |d| d.read_inbox_max_id);
+        }
+        self.command_sender
+            .try_send(Command::RefreshMessages(chat.clone()))?;
+        self.command_sender.try_send(Command::MarkAsRead(chat))?;
+        Ok(())
+    }
+
+    /// Returns read state of the active chat. Inbox state is one from
+    /// the moment the chat was opened, before it was marked as read.
+    pub fn get_active_chat_read_state(&self) -> Result<Option<ChatReadState>> {
+        let i = self.shared_state.lock().unwrap();
+        let Some(chat) = i.active_chat.as_ref() else {
+            return Ok(None);
+        };
+        let read_outbox_max_id = i
+            .storage
+            .select_raw_dialog(&chat.pack().to_peer())?
+            .map_or(0, |d| d.read_outbox_max_id);
+        Ok(Some(ChatReadState {
+            read_inbox_max_id: i.active_chat_read_inbox_max_id,
+            read_outbox_max_id,
+        }))
+    }
+
+    /// Starts searching messages on server, in the active chat or in all
+    /// chats. Results are returned by `get_server_search_results`.
+    pub fn start_server_search(&self, text: &str, in_active_chat: bool) -> Result<()> {
+        let chat = if in_active_chat {
+            Some(
+                self.get_active_chat()
+                    .ok_or_else(|| eyre!("No active chat to search in"))?,
+            )
+        } else {
+            None
+        };
+        let mut i = self.shared_state.lock().unwrap();
+        if let Some(handle) = i.server_search.abort_handle.take() {
+            handle.abort();
+        }
+        i.server_search.generation += 1;
+        i.server_search.results.clear();
+        i.server_search.in_progress = true;
+        let command = Command::Search {
+            generation: i.server_search.generation,
+            text: text.to_string(),
+            chat,
+        };
+        if let Err(e) = self.command_sender.try_send(command) {
+            i.server_search.in_progress = false;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Returns messages found by the last server search so far, and
+    /// whether the search is still in progress.
+    pub fn get_server_search_results(&self) -> (Vec<StoredMessage>, bool) {
+        let i = self.shared_state.lock().unwrap();
+        (i.server_search.results.clone(), i.server_search.in_progress)
+    }
+
+    /// Searches messages in the local cache.
+    pub fn search_local_messages(&self, text: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        let i = self.shared_state.lock().unwrap();
+        i.storage.search_messages(text, limit)
+    }
+
+    pub fn get_connection_state(&self) -> ConnectionState {
+        let i = self.shared_state.lock().unwrap();
+        i.connection_state
+    }
+
+    /// Puts text message to the outbox of the active chat. It is sent
+    /// at once, if connected, or when connection returns.
+    pub fn send_message(&self, text: String, reply_to: Option<i32>) -> Result<()> {
+        let chat = self
+            .get_active_chat()
+            .ok_or_else(|| eyre!("No active chat to send message to"))?;
+        {
+            let i = self.shared_state.lock().unwrap();
+            let date = chrono::Utc::now().timestamp() as i32;
+            i.storage
+                .add_outbox_message(&chat.pack(), &text, reply_to, date)?;
+        }
+        self.request_outbox_sending();
+        Ok(())
+    }
+
+    /// Returns messages of the active chat, that are not sent yet, oldest
+    /// first.
+    pub fn get_active_chat_outbox(&self) -> Result<Vec<OutboxMessage>> {
+        let i = self.shared_state.lock().unwrap();
+        let Some(chat) = i.active_chat.as_ref() else {
+            return Ok(Vec::new());
+        };
+        i.storage
+            .select_outbox_messages(storage::chat_peer_id(&chat.pack()))
+    }
+
+    /// Sends again messages of the active chat, that failed to be sent.
+    pub fn retry_failed_messages(&self) -> Result<()> {
+        let Some(chat) = self.get_active_chat() else {
+            return Ok(());
+        };
+        let failed_upload_ids: Vec<u64> = {
+            let mut i = self.shared_state.lock().unwrap();
+            i.storage
+                .retry_failed_outbox_messages(storage::chat_peer_id(&chat.pack()))?;
+            i.uploads
+                .iter_mut()
+                .filter(|upload| upload.failed && upload.chat.id == chat.id())
+                .map(|upload| {
+                    upload.failed = false;
+                    upload.id
+                })
+                .collect()
+        };
+        self.request_outbox_sending();
+        for upload_id in failed_upload_ids {
+            self.command_sender.try_send(Command::SendFile(upload_id))?;
+        }
+        Ok(())
+    }
+
+    /// Sends file to the active chat with the caption. Upload is done in
+    /// background, its progress is returned by `get_active_chat_uploads`.
+    pub fn send_file(&self, path: PathBuf, kind: FileKind, caption: String) -> Result<()> {
+        let chat = self
+            .get_active_chat()
+            .ok_or_else(|| eyre!("No active chat to send file to"))?;
+        let metadata = std::fs::metadata(&path)?;
+        if !metadata.is_file() {
+            return Err(eyre!("{} is not a file", path.display()));
+        }
+        let upload_id = {
+            let mut i = self.shared_state.lock().unwrap();
+            let upload_id = i.next_upload_id;
+            i.next_upload_id += 1;
+            i.uploads.push(Upload {
+                id: upload_id,
+                chat: chat.pack(),
+                path,
+                kind,
+                caption,
+                uploaded: Arc::new(AtomicU64::new(0)),
+                total: metadata.len(),
+                failed: false,
+                abort_handle: None,
+            });
+            upload_id
+        };
+        self.command_sender.try_send(Command::SendFile(upload_id))?;
+        Ok(())
+    }
+
+    /// Returns files being sent to the active chat, oldest first.
+    pub fn get_active_chat_uploads(&self) -> Vec<UploadProgress> {
+        let i = self.shared_state.lock().unwrap();
+        let Some(chat) = i.active_chat.as_ref() else {
+            return Vec::new();
+        };
+        i.uploads
+            .iter()
+            .filter(|upload| upload.chat.id == chat.id())
+            .map(|upload| UploadProgress {
+                id: upload.id,
+                file_name: upload
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                kind: upload.kind,
+                caption: upload.caption.clone(),
+                uploaded: upload.uploaded.load(Ordering::Relaxed),
+                total: upload.total,
+                failed: upload.failed,
+            })
+            .collect()
+    }
+
+    /// Stops sending the file, if it is not sent yet.
+    pub fn cancel_upload(&self, upload_id: u64) {
+        let mut i = self.shared_state.lock().unwrap();
+        let Some(index) = i.uploads.iter().position(|upload| upload.id == upload_id) else {
+            return;
+        };
+        let upload = i.uploads.remove(index);
+        if let Some(abort_handle) = upload.abort_handle {
+            abort_handle.abort();
+        }
+    }
+
+    fn request_outbox_sending(&self) {
+        // Message is stored already, it is sent with the next outbox
+        // sending then.
+        if let Err(e) = self.command_sender.try_send(Command::SendOutbox) {
+            log::warn!("Failed request outbox sending {:?}", e);
+        }
+    }
+
+    /// Forwards message of the active chat to another chat.
+    pub fn forward_message(&self, message_id: i32, to: Chat) -> Result<()> {
+        let from = self
+            .get_active_chat()
+            .ok_or_else(|| eyre!("No active chat to forward message from"))?;
+        self.command_sender.try_send(Command::Forward {
+            from: from.pack(),
+            message_id,
+            to: to.pack(),
+        })?;
+        Ok(())
+    }
+
+    /// Replaces text of the message, sent by user to the active chat.
+    pub fn edit_message(&self, message_id: i32, text: String) -> Result<()> {
+        let chat = self
+            .get_active_chat()
+            .ok_or_else(|| eyre!("No active chat to edit message in"))?;
+        self.command_sender.try_send(Command::Edit {
+            chat,
+            message_id,
+            text,
+        })?;
+        Ok(())
+    }
+
+    /// Deletes message of the active chat for the user, or, if `revoke` is
+    /// set, for all participants.
+    pub fn delete_message(&self, message_id: i32, revoke: bool) -> Result<()> {
+        let chat = self
+            .get_active_chat()
+            .ok_or_else(|| eyre!("No active chat to delete message in"))?;
+        self.command_sender.try_send(Command::Delete {
+            chat,
+            message_id,
+            revoke,
+        })?;
+        Ok(())
+    }
+
+    /// Starts download of the message media to the media cache, unless
+    /// it is downloaded or being downloaded already.
+    pub fn download_media(&self, message: &StoredMessage) -> Result<()> {
+        let Some(media) = message.media() else {
+            return Ok(());
+        };
+        let Some(media_key) = media_cache::media_key(&media) else {
+            return Ok(());
+        };
+        if let Some(DownloadProgress {
+            state: DownloadState::Queued | DownloadState::Downloading | DownloadState::Done(_),
+            ..
+        }) = self.get_download_progress(&media)?
+        {
+            return Ok(());
+        }
+        self.shared_state.lock().unwrap().downloads.insert(
+            media_key,
+            DownloadProgress {
+                state: DownloadState::Queued,
+                downloaded: 0,
+                total: media_cache::media_size(&media),
+            },
+        );
+        self.command_sender.try_send(Command::Download {
+            media: Box::new(media),
+            chat: message.chat().pack(),
+            message_id: message.id(),
+        })?;
+        Ok(())
+    }
+
+    /// Returns progress of the media download, or None if the media is
+    /// neither downloaded nor requested to be.
+    pub fn get_download_progress(&self, media: &Media) -> Result<Option<DownloadProgress>> {
+        let Some(media_key) = media_cache::media_key(media) else {
+            return Ok(None);
+        };
+        let mut i = self.shared_state.lock().unwrap();
+        if let Some(progress) = i.downloads.get(&media_key) {
+            return Ok(Some(progress.clone()));
+        }
+        if i.missing_media.contains(&media_key) {
+            return Ok(None);
+        }
+        // Downloaded before the app start, looked up once, later changes
+        // come with download events.
+        let media_file = i.storage.select_media_file(&media_key)?;
+        let progress = media_file.and_then(|media_file| {
+            let path = i.media_cache.file_path(&media_file);
+            path.exists().then_some(DownloadProgress {
+                state: DownloadState::Done(path),
+                downloaded: media_file.size,
+                total: Some(media_file.size),
+            })
+        });
+        match &progress {
+            Some(progress) => {
+                i.downloads.insert(media_key, progress.clone());
+            }
+            None => {
+                i.missing_media.insert(media_key);
+            }
+        }
+        Ok(progress)
+    }
+
+    /// Runs blocking `work`, e.g. image decoding, on a separate thread and
+    /// requests redraw when it is done, so its result is shown.
+    pub fn spawn_blocking<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let redraw_sender = self.redraw_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            work();
+            Self::request_redraw(&redraw_sender);
+        });
     }
 
     pub async fn stop(self) -> Result<()> {
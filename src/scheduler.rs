@@ -0,0 +1,173 @@
+use grammers_client::InvocationError;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Background requests are sent not more often than that, so bulk fetches
+// don't trip flood limits.
+const BACKGROUND_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    // Requested by user, who waits for the result.
+    Interactive,
+    // Sync, that may be done later.
+    Background,
+}
+
+/// Orders requests to Telegram: interactive ones go first, background ones
+/// are rate limited, and ones failed with FLOOD_WAIT are retried after the
+/// wait, without blocking others.
+pub struct RequestScheduler<T> {
+    interactive: VecDeque<T>,
+    background: VecDeque<T>,
+    // Requests to retry, with the time they may be sent at.
+    delayed: Vec<(Instant, Priority, T)>,
+    last_background: Option<Instant>,
+}
+
+impl<T> RequestScheduler<T> {
+    pub fn new() -> Self {
+        Self {
+            interactive: VecDeque::new(),
+            background: VecDeque::new(),
+            delayed: Vec::new(),
+            last_background: None,
+        }
+    }
+
+    pub fn push(&mut self, request: T, priority: Priority) {
+        match priority {
+            Priority::Interactive => self.interactive.push_back(request),
+            Priority::Background => self.background.push_back(request),
+        }
+    }
+
+    /// Puts request back to be sent after `wait`.
+    pub fn delay(&mut self, request: T, priority: Priority, wait: Duration) {
+        self.delayed
+            .push((Instant::now() + wait, priority, request));
+    }
+
+    /// Returns request, that should be sent now, if any.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<(T, Priority)> {
+        self.move_due_delayed(now);
+        if let Some(request) = self.interactive.pop_front() {
+            return Some((request, Priority::Interactive));
+        }
+        if self
+            .background_allowed_at(now)
+            .is_some_and(|time| time <= now)
+        {
+            self.last_background = Some(now);
+            return self
+                .background
+                .pop_front()
+                .map(|request| (request, Priority::Background));
+        }
+        None
+    }
+
+    /// Returns time, when `pop_ready` returns a request next time, or None
+    /// if there are no requests.
+    pub fn next_ready_time(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let queued_time = if self.interactive.is_empty() {
+            self.background_allowed_at(now)
+        } else {
+            Some(now)
+        };
+        let delayed_time = self.delayed.iter().map(|(time, _, _)| *time).min();
+        [queued_time, delayed_time].into_iter().flatten().min()
+    }
+
+    // Returns None if there are no background requests.
+    fn background_allowed_at(&self, now: Instant) -> Option<Instant> {
+        if self.background.is_empty() {
+            return None;
+        }
+        match self.last_background {
+            Some(last) => Some(last + BACKGROUND_REQUEST_INTERVAL),
+            None => Some(now),
+        }
+    }
+
+    fn move_due_delayed(&mut self, now: Instant) {
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].0 <= now {
+                let (_, priority, request) = self.delayed.remove(i);
+                self.push(request, priority);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Returns time to wait before retry, if the request failed because of
+/// flood limits.
+pub fn flood_wait(error: &eyre::Report) -> Option<Duration> {
+    match error.downcast_ref::<InvocationError>() {
+        // grammers strips the number from "FLOOD_WAIT_X" into value.
+        Some(InvocationError::Rpc(rpc_error))
+            if rpc_error.name == "FLOOD_WAIT" || rpc_error.name == "FLOOD_PREMIUM_WAIT" =>
+        {
+            Some(Duration::from_secs(u64::from(rpc_error.value.unwrap_or(1))))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interactive_requests_go_before_background() {
+        let mut scheduler = RequestScheduler::new();
+        scheduler.push(1, Priority::Background);
+        scheduler.push(2, Priority::Interactive);
+        let now = Instant::now();
+
+        assert_eq!(scheduler.pop_ready(now), Some((2, Priority::Interactive)));
+        assert_eq!(scheduler.pop_ready(now), Some((1, Priority::Background)));
+        assert_eq!(scheduler.pop_ready(now), None);
+        assert_eq!(scheduler.next_ready_time(), None);
+    }
+
+    #[test]
+    fn background_requests_are_rate_limited() {
+        let mut scheduler = RequestScheduler::new();
+        scheduler.push(1, Priority::Background);
+        scheduler.push(2, Priority::Background);
+        let now = Instant::now();
+
+        assert_eq!(scheduler.pop_ready(now), Some((1, Priority::Background)));
+        assert_eq!(scheduler.pop_ready(now), None);
+        assert_eq!(
+            scheduler.next_ready_time(),
+            Some(now + BACKGROUND_REQUEST_INTERVAL)
+        );
+        assert_eq!(
+            scheduler.pop_ready(now + BACKGROUND_REQUEST_INTERVAL),
+            Some((2, Priority::Background))
+        );
+    }
+
+    #[test]
+    fn delayed_requests_wait_for_their_time() {
+        let mut scheduler = RequestScheduler::new();
+        scheduler.delay(1, Priority::Interactive, Duration::from_secs(5));
+        scheduler.push(2, Priority::Interactive);
+        let now = Instant::now();
+
+        assert_eq!(scheduler.pop_ready(now), Some((2, Priority::Interactive)));
+        assert_eq!(scheduler.pop_ready(now), None);
+        let ready_time = scheduler.next_ready_time().unwrap();
+        assert!(ready_time > now);
+        assert_eq!(
+            scheduler.pop_ready(ready_time),
+            Some((1, Priority::Interactive))
+        );
+    }
+}
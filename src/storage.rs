@@ -1,24 +1,252 @@
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use eyre::eyre;
-use grammers_client::session::Session;
-use grammers_client::types::{Channel, Chat, Dialog, Group, Message, MessageDeletion, User};
+use grammers_client::session::{PackedChat, Session, UpdateState};
+use grammers_client::types::{Channel, Chat, Dialog, Group, Media, Message, MessageDeletion, User};
+use grammers_client::ChatMap;
 use grammers_tl_types as tl_types;
 use grammers_tl_types::Cursor;
 use grammers_tl_types::Deserializable;
 use grammers_tl_types::Serializable;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Message with its sender and chat, usable without connection to
+/// Telegram, unlike grammers Message, which can be built only with a
+/// connected client.
+#[derive(Clone)]
+pub struct StoredMessage {
+    pub raw: tl_types::types::Message,
+    // Known senders and chats of the message.
+    chats: Arc<ChatMap>,
+}
+
+impl StoredMessage {
+    pub fn from_message(message: &Message) -> Self {
+        let mut users = Vec::new();
+        let mut chats = Vec::new();
+        for chat in message.sender().into_iter().chain([message.chat()]) {
+            push_raw_chat(chat, &mut users, &mut chats);
+        }
+        Self {
+            raw: message.raw.clone(),
+            chats: ChatMap::new(users, chats),
+        }
+    }
+
+    pub fn id(&self) -> i32 {
+        self.raw.id
+    }
+
+    pub fn text(&self) -> &str {
+        &self.raw.message
+    }
+
+    /// Formatting of the text.
+    pub fn entities(&self) -> &[tl_types::enums::MessageEntity] {
+        self.raw.entities.as_deref().unwrap_or_default()
+    }
+
+    pub fn outgoing(&self) -> bool {
+        self.raw.out
+    }
+
+    pub fn date(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(i64::from(self.raw.date), 0).unwrap_or_default()
+    }
+
+    pub fn edit_date(&self) -> Option<DateTime<Utc>> {
+        self.raw
+            .edit_date
+            .and_then(|date| DateTime::from_timestamp(i64::from(date), 0))
+    }
+
+    pub fn edit_hide(&self) -> bool {
+        self.raw.edit_hide
+    }
+
+    pub fn forward_header(&self) -> Option<&tl_types::enums::MessageFwdHeader> {
+        self.raw.fwd_from.as_ref()
+    }
+
+    /// Returns media attached to the message, if grammers supports it.
+    pub fn media(&self) -> Option<Media> {
+        self.raw.media.clone().and_then(Media::from_raw)
+    }
+
+    pub fn reply_to_message_id(&self) -> Option<i32> {
+        match &self.raw.reply_to {
+            Some(tl_types::enums::MessageReplyHeader::Header(header)) => header.reply_to_msg_id,
+            _ => None,
+        }
+    }
+
+    /// Chat the message belongs to. Chats not stored yet are returned as
+    /// stubs with empty names, like grammers does.
+    pub fn chat(&self) -> Chat {
+        self.find_chat(&self.raw.peer_id)
+    }
+
+    pub fn sender(&self) -> Option<Chat> {
+        // Incoming messages in private chats have no from_id, the sender
+        // is the chat itself.
+        let from = self.raw.from_id.as_ref().or_else(|| {
+            (!self.raw.out && matches!(self.raw.peer_id, tl_types::enums::Peer::User(_)))
+                .then_some(&self.raw.peer_id)
+        })?;
+        Some(self.find_chat(from))
+    }
+
+    fn find_chat(&self, peer: &tl_types::enums::Peer) -> Chat {
+        if let Some(chat) = self.chats.get(peer) {
+            return chat.clone();
+        }
+        match peer {
+            tl_types::enums::Peer::User(user) => Chat::User(User::from_raw(
+                tl_types::types::UserEmpty { id: user.user_id }.into(),
+            )),
+            tl_types::enums::Peer::Chat(group) => Chat::Group(Group::from_raw(
+                tl_types::types::ChatEmpty { id: group.chat_id }.into(),
+            )),
+            tl_types::enums::Peer::Channel(channel) => {
+                // Messages of broadcast channels are posts, while members
+                // of megagroups write for themselves. Channel, other than
+                // the chat, may be only a sender, and only broadcast
+                // channels send messages as themselves.
+                let broadcast = if *peer == self.raw.peer_id {
+                    self.raw.post
+                } else {
+                    true
+                };
+                Chat::from_raw(
+                    tl_types::types::ChannelForbidden {
+                        broadcast,
+                        megagroup: !broadcast,
+                        id: channel.channel_id,
+                        access_hash: 0,
+                        title: String::new(),
+                        until_date: None,
+                    }
+                    .into(),
+                )
+            }
+        }
+    }
+}
+
+// Adds raw entity of the chat to users or chats, as ChatMap::new takes them.
+fn push_raw_chat(
+    chat: Chat,
+    users: &mut Vec<tl_types::enums::User>,
+    chats: &mut Vec<tl_types::enums::Chat>,
+) {
+    match chat {
+        Chat::User(user) => users.push(tl_types::enums::User::User(user.raw)),
+        Chat::Group(group) => chats.push(group.raw),
+        Chat::Channel(channel) => chats.push(tl_types::enums::Chat::Channel(channel.raw)),
+    }
+}
 
 pub struct Storage {
     connection: rusqlite::Connection,
 }
 
-fn peer_id(peer: &tl_types::enums::Peer) -> i64 {
+// Not all variants are used by UI yet.
+#[allow(dead_code)]
+pub enum MessagesOrder {
+    IdAscending,
+    IdDescending,
+    // Dates of forwarded and imported messages may go not in order of IDs.
+    DateAscending,
+    DateDescending,
+}
+
+// Message ID bounds are exclusive.
+pub enum MessagesRange {
+    All,
+    Before(i32),
+    After(i32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutboxState {
+    // Waits to be sent, when connected.
+    Pending,
+    // Sending failed, waits for user to retry.
+    Failed,
+}
+
+/// Message, composed by user, that is not sent yet.
+pub struct OutboxMessage {
+    // Temporary ID, used until server assigns the real one.
+    pub local_id: i64,
+    pub chat: PackedChat,
+    pub text: String,
+    // ID of the message it replies to.
+    pub reply_to: Option<i32>,
+    pub date: i32,
+    pub state: OutboxState,
+    // Sent with the message, so server ignores repeated sending and
+    // reports the ID it assigned to the message.
+    pub random_id: i64,
+}
+
+/// Downloaded media file, stored in the media cache.
+#[derive(Debug, PartialEq)]
+pub struct MediaFile {
+    // Hash of the file content, which names the file in the cache.
+    pub content_hash: String,
+    // Name of the file, as it was sent.
+    pub file_name: String,
+    pub size: u64,
+}
+
+pub struct MessagesQuery {
+    // Marked ID of the peer, see chat_peer_id.
+    pub peer_id: i64,
+    pub range: MessagesRange,
+    pub order: MessagesOrder,
+    pub limit: usize,
+}
+
+// Bare IDs of users, groups and channels may be equal, so messages are
+// stored with IDs marked as in Bot API: negative for groups and below
+// -CHANNEL_ID_MARK for channels.
+const CHANNEL_ID_MARK: i64 = 1000000000000;
+// Version of the database layout, kept in "user_version" pragma.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Returns marked ID of the peer, see CHANNEL_ID_MARK.
+pub fn peer_id(peer: &tl_types::enums::Peer) -> i64 {
     match peer {
         tl_types::enums::Peer::User(user) => user.user_id,
-        tl_types::enums::Peer::Chat(group) => group.chat_id,
-        tl_types::enums::Peer::Channel(channel) => channel.channel_id,
+        tl_types::enums::Peer::Chat(group) => -group.chat_id,
+        tl_types::enums::Peer::Channel(channel) => -(CHANNEL_ID_MARK + channel.channel_id),
     }
 }
 
+/// Returns pts of channels in the update state by their IDs. grammers
+/// doesn't export type of channel states, so they are read from their
+/// serialized form: constructor ID, channel ID and pts.
+pub fn channel_pts(state: &UpdateState) -> HashMap<i64, i32> {
+    state
+        .channels
+        .iter()
+        .filter_map(|channel| {
+            let data = channel.to_bytes();
+            let channel_id = i64::from_le_bytes(data.get(4..12)?.try_into().ok()?);
+            let pts = i32::from_le_bytes(data.get(12..16)?.try_into().ok()?);
+            Some((channel_id, pts))
+        })
+        .collect()
+}
+
+/// Returns ID, that messages, history ranges and outbox messages of the
+/// chat are stored with.
+pub fn chat_peer_id(chat: &PackedChat) -> i64 {
+    peer_id(&chat.to_peer())
+}
+
 impl Storage {
     pub fn new(db_file_path: &std::path::Path) -> Result<Self> {
         let connection = rusqlite::Connection::open(db_file_path)?;
@@ -28,7 +256,28 @@ impl Storage {
         Self::ensure_blob_table(&connection, "dialogs")?;
         Self::ensure_blob_table(&connection, "session")?;
         Self::ensure_messages_table(&connection)?;
+        Self::ensure_history_ranges_table(&connection)?;
+        let message_peers_created = Self::ensure_message_peers_table(&connection)?;
+        Self::ensure_outbox_table(&connection)?;
+        Self::ensure_media_files_table(&connection)?;
+        Self::ensure_update_state_table(&connection)?;
+        let search_index_created = Self::ensure_search_index_table(&connection)?;
         let result = Self { connection };
+        let version = result
+            .connection
+            .query_row("PRAGMA user_version;", (), |r| r.get::<usize, i64>(0))?;
+        if version < 1 {
+            result.mark_peer_ids()?;
+        }
+        if message_peers_created || version < 1 {
+            result.rebuild_message_peers()?;
+        }
+        result
+            .connection
+            .execute(&format!("PRAGMA user_version = {};", SCHEMA_VERSION), ())?;
+        if search_index_created {
+            result.rebuild_search_index()?;
+        }
         Ok(result)
     }
 
@@ -46,6 +295,205 @@ impl Storage {
             (peer_id INTEGER, message_id INTEGER, date INTEGER, data BLOB,
              PRIMARY KEY(peer_id, message_id));";
         connection.execute(statement, ())?;
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS messages_by_date ON messages(peer_id, date);",
+            (),
+        )?;
+        Ok(())
+    }
+
+    // Each row states, that all messages of the peer with IDs in
+    // [first_id, last_id] are stored locally. Spaces between rows are gaps
+    // in the history, that must be fetched from server.
+    // first_id = 0 means that range reaches the beginning of the history.
+    fn ensure_history_ranges_table(connection: &rusqlite::Connection) -> Result<()> {
+        let statement = "CREATE TABLE IF NOT EXISTS history_ranges
+            (peer_id INTEGER, first_id INTEGER, last_id INTEGER);";
+        connection.execute(statement, ())?;
+        Ok(())
+    }
+
+    // Private chats and basic groups share per-account message ID sequence,
+    // unlike channels and megagroups, where IDs are unique per channel.
+    // So for such messages the ID alone identifies the message, and
+    // this table maps it to the peer.
+    // Returns true if the table was just created.
+    fn ensure_message_peers_table(connection: &rusqlite::Connection) -> Result<bool> {
+        let exists = connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'message_peers';",
+            (),
+            |r| r.get::<usize, i64>(0),
+        )? > 0;
+        if !exists {
+            connection.execute(
+                "CREATE TABLE message_peers (message_id INTEGER PRIMARY KEY, peer_id INTEGER);",
+                (),
+            )?;
+        }
+        Ok(!exists)
+    }
+
+    // Messages to send, in order they were composed. Chat is stored packed,
+    // as messages are sent to it without loading it from other tables.
+    fn ensure_outbox_table(connection: &rusqlite::Connection) -> Result<()> {
+        let statement = "CREATE TABLE IF NOT EXISTS outbox
+            (local_id INTEGER PRIMARY KEY AUTOINCREMENT, peer_id INTEGER,
+             chat BLOB, text TEXT, date INTEGER, failed INTEGER, reply_to INTEGER,
+             random_id INTEGER, in_flight INTEGER);";
+        connection.execute(statement, ())?;
+        // Tables created before replies and random IDs were supported lack
+        // the columns.
+        Self::ensure_outbox_column(connection, "reply_to")?;
+        if Self::ensure_outbox_column(connection, "random_id")? {
+            connection.execute("UPDATE outbox SET random_id = random();", ())?;
+        }
+        if Self::ensure_outbox_column(connection, "in_flight")? {
+            // Requests, made by older versions, may have reached the server.
+            connection.execute("UPDATE outbox SET in_flight = 1;", ())?;
+        }
+        Ok(())
+    }
+
+    // Returns true if the column had to be added.
+    fn ensure_outbox_column(connection: &rusqlite::Connection, name: &str) -> Result<bool> {
+        let exists = connection.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('outbox') WHERE name = ?;",
+            [name],
+            |r| r.get::<usize, i64>(0),
+        )? > 0;
+        if !exists {
+            connection.execute(
+                &format!("ALTER TABLE outbox ADD COLUMN {} INTEGER;", name),
+                (),
+            )?;
+        }
+        Ok(!exists)
+    }
+
+    // Index of the media cache. Media key identifies photo or document on
+    // Telegram servers, many of them may have the same content.
+    fn ensure_media_files_table(connection: &rusqlite::Connection) -> Result<()> {
+        let statement = "CREATE TABLE IF NOT EXISTS media_files
+            (media_key TEXT PRIMARY KEY, content_hash TEXT, file_name TEXT, size INTEGER);";
+        connection.execute(statement, ())?;
+        Ok(())
+    }
+
+    // Sequence numbers and date of the last received updates, so updates
+    // missed while offline are fetched after them. States of channels are
+    // stored serialized, grammers doesn't expose their type.
+    fn ensure_update_state_table(connection: &rusqlite::Connection) -> Result<()> {
+        let statement = "CREATE TABLE IF NOT EXISTS update_state
+            (id INTEGER PRIMARY KEY, pts INTEGER, qts INTEGER, seq INTEGER, date INTEGER,
+             channels BLOB);";
+        connection.execute(statement, ())?;
+        Ok(())
+    }
+
+    // Full-text index of message texts. Row IDs are equal to row IDs of
+    // corresponding rows in "messages" table.
+    // Returns true if the index was just created.
+    fn ensure_search_index_table(connection: &rusqlite::Connection) -> Result<bool> {
+        let exists = connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts';",
+            (),
+            |r| r.get::<usize, i64>(0),
+        )? > 0;
+        if !exists {
+            connection.execute("CREATE VIRTUAL TABLE messages_fts USING fts5(text);", ())?;
+        }
+        Ok(!exists)
+    }
+
+    // Fills search index with already stored messages, used when opening
+    // databases created before the index was introduced.
+    fn rebuild_search_index(&self) -> Result<()> {
+        let mut select_stmt = self
+            .connection
+            .prepare("SELECT rowid, data FROM messages;")?;
+        let mut insert_stmt = self
+            .connection
+            .prepare_cached("INSERT INTO messages_fts(rowid, text) VALUES (?, ?);")?;
+        let mut rows = select_stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let rowid = row.get::<usize, i64>(0)?;
+            let data = row.get::<usize, Vec<u8>>(1)?;
+            let raw = tl_types::types::Message::deserialize(&mut Cursor::from_slice(&data))?;
+            if !raw.message.is_empty() {
+                insert_stmt.execute((rowid, raw.message))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces bare peer IDs, stored before version 1, with marked ones.
+    // Peers of messages and outbox messages are known from their data.
+    // History ranges are dropped, as their peer types are unknown, so the
+    // history is fetched again.
+    fn mark_peer_ids(&self) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let mut message_peer_ids = Vec::new();
+        {
+            let mut select_stmt = self
+                .connection
+                .prepare("SELECT rowid, data FROM messages;")?;
+            let mut rows = select_stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let rowid = row.get::<usize, i64>(0)?;
+                let data = row.get::<usize, Vec<u8>>(1)?;
+                let raw = tl_types::types::Message::deserialize(&mut Cursor::from_slice(&data))?;
+                message_peer_ids.push((peer_id(&raw.peer_id), rowid));
+            }
+        }
+        let mut update_stmt = self
+            .connection
+            .prepare("UPDATE messages SET peer_id = ? WHERE rowid = ?;")?;
+        for params in message_peer_ids {
+            update_stmt.execute(params)?;
+        }
+        let mut outbox_peer_ids = Vec::new();
+        {
+            let mut select_stmt = self
+                .connection
+                .prepare("SELECT local_id, chat FROM outbox;")?;
+            let mut rows = select_stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let local_id = row.get::<usize, i64>(0)?;
+                let chat_data = row.get::<usize, Vec<u8>>(1)?;
+                let chat = PackedChat::from_bytes(&chat_data)
+                    .map_err(|_| eyre!("DB damaged, bad outbox chat"))?;
+                outbox_peer_ids.push((chat_peer_id(&chat), local_id));
+            }
+        }
+        let mut update_stmt = self
+            .connection
+            .prepare("UPDATE outbox SET peer_id = ? WHERE local_id = ?;")?;
+        for params in outbox_peer_ids {
+            update_stmt.execute(params)?;
+        }
+        self.connection.execute("DELETE FROM history_ranges;", ())?;
+        self.connection.execute("DELETE FROM message_peers;", ())?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Maps already stored messages to their peers, used when opening
+    // databases created before the mapping was introduced. Peer type is
+    // known only from the message itself, as IDs of users, groups and
+    // channels may be equal.
+    fn rebuild_message_peers(&self) -> Result<()> {
+        let mut select_stmt = self.connection.prepare("SELECT data FROM messages;")?;
+        let mut insert_stmt = self.connection.prepare_cached(
+            "INSERT OR REPLACE INTO message_peers(message_id, peer_id) VALUES (?, ?);",
+        )?;
+        let mut rows = select_stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let data = row.get::<usize, Vec<u8>>(0)?;
+            let raw = tl_types::types::Message::deserialize(&mut Cursor::from_slice(&data))?;
+            if !matches!(raw.peer_id, tl_types::enums::Peer::Channel(_)) {
+                insert_stmt.execute((raw.id, peer_id(&raw.peer_id)))?;
+            }
+        }
         Ok(())
     }
 
@@ -64,7 +512,7 @@ impl Storage {
             Chat::Channel(channel) => {
                 assert!(channel.id() >= 1);
                 assert!(channel.id() <= 997852516352);
-                -(1000000000000 + channel.id())
+                -(CHANNEL_ID_MARK + channel.id())
             }
         }
     }
@@ -100,6 +548,166 @@ impl Storage {
         Ok(result)
     }
 
+    // Returns IDs, that dialog with the peer may have in "dialogs" table.
+    fn dialog_ids(peer: &tl_types::enums::Peer) -> Vec<i64> {
+        match peer {
+            tl_types::enums::Peer::User(user) => vec![user.user_id],
+            tl_types::enums::Peer::Chat(group) => vec![-group.chat_id],
+            // Megagroups are stored as groups, see to_bot_id.
+            tl_types::enums::Peer::Channel(channel) => {
+                vec![-(CHANNEL_ID_MARK + channel.channel_id), -channel.channel_id]
+            }
+        }
+    }
+
+    /// Returns stored dialog with the peer.
+    pub fn select_raw_dialog(
+        &self,
+        peer: &tl_types::enums::Peer,
+    ) -> Result<Option<tl_types::types::Dialog>> {
+        Ok(self.find_raw_dialog(peer)?.map(|(_, raw)| raw))
+    }
+
+    // Returns stored dialog with the peer and its ID in "dialogs" table.
+    fn find_raw_dialog(
+        &self,
+        peer: &tl_types::enums::Peer,
+    ) -> Result<Option<(i64, tl_types::types::Dialog)>> {
+        let mut select_stmt = self
+            .connection
+            .prepare_cached("SELECT data FROM dialogs WHERE id = ?;")?;
+        for id in Self::dialog_ids(peer) {
+            let mut rows = select_stmt.query([id])?;
+            if let Some(row) = rows.next()? {
+                let data = row.get::<usize, Vec<u8>>(0)?;
+                let raw = tl_types::enums::Dialog::deserialize(&mut Cursor::from_slice(&data))?;
+                if let tl_types::enums::Dialog::Dialog(raw) = raw {
+                    return Ok(Some((id, raw)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Applies `modify` to the stored dialog with the peer. Returns false
+    /// if there is no such dialog.
+    pub fn update_dialog<F>(&self, peer: &tl_types::enums::Peer, modify: F) -> Result<bool>
+    where
+        F: FnOnce(&mut tl_types::types::Dialog),
+    {
+        let Some((id, mut raw)) = self.find_raw_dialog(peer)? else {
+            return Ok(false);
+        };
+        modify(&mut raw);
+        self.save_generic("dialogs", id, &tl_types::enums::Dialog::Dialog(raw))?;
+        Ok(true)
+    }
+
+    /// Applies `modify` to all stored dialogs.
+    pub fn update_all_dialogs<F>(&self, mut modify: F) -> Result<()>
+    where
+        F: FnMut(&mut tl_types::types::Dialog),
+    {
+        let mut select_stmt = self
+            .connection
+            .prepare_cached("SELECT id, data FROM dialogs;")?;
+        let mut rows = select_stmt.query([])?;
+        let mut modified = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id = row.get::<usize, i64>(0)?;
+            let data = row.get::<usize, Vec<u8>>(1)?;
+            let raw = tl_types::enums::Dialog::deserialize(&mut Cursor::from_slice(&data))?;
+            if let tl_types::enums::Dialog::Dialog(mut raw) = raw {
+                modify(&mut raw);
+                modified.push((id, raw));
+            }
+        }
+        for (id, raw) in modified {
+            self.save_generic("dialogs", id, &tl_types::enums::Dialog::Dialog(raw))?;
+        }
+        Ok(())
+    }
+
+    /// Updates top message and unread counters of the dialog with the new
+    /// message. Adds the dialog if it is not stored yet. Message is not
+    /// counted as unread if it is `read`, e.g. arrived to the open chat.
+    pub fn add_message_to_dialog(&self, message: &Message, read: bool) -> Result<()> {
+        if self.add_raw_message_to_dialog(&message.raw, read)? {
+            return Ok(());
+        }
+        let chat = message.chat();
+        if chat.name().is_empty() {
+            // Stub chat can't be shown, dialog appears on next dialogs fetch.
+            log::info!("Not adding dialog for unknown chat {}", chat.id());
+            return Ok(());
+        }
+        let dialog = Dialog {
+            raw: tl_types::enums::Dialog::Dialog(Self::new_raw_dialog(&message.raw, read)),
+            chat,
+            last_message: None,
+        };
+        self.save_dialog(&dialog)
+    }
+
+    // Returns false if dialog of the message is not stored.
+    fn add_raw_message_to_dialog(
+        &self,
+        message: &tl_types::types::Message,
+        read: bool,
+    ) -> Result<bool> {
+        self.update_dialog(&message.peer_id, |dialog| {
+            // Already counted, or older message, fetched with history.
+            if message.id <= dialog.top_message {
+                return;
+            }
+            dialog.top_message = message.id;
+            if read {
+                dialog.read_inbox_max_id = std::cmp::max(dialog.read_inbox_max_id, message.id);
+            }
+            // Read state of outgoing messages changes by separate updates.
+            if !message.out && message.id > dialog.read_inbox_max_id {
+                dialog.unread_count += 1;
+                if message.mentioned {
+                    dialog.unread_mentions_count += 1;
+                }
+            }
+        })
+    }
+
+    fn new_raw_dialog(message: &tl_types::types::Message, read: bool) -> tl_types::types::Dialog {
+        let unread = !message.out && !read;
+        tl_types::types::Dialog {
+            pinned: false,
+            unread_mark: false,
+            view_forum_as_messages: false,
+            peer: message.peer_id.clone(),
+            top_message: message.id,
+            read_inbox_max_id: if read { message.id } else { 0 },
+            read_outbox_max_id: 0,
+            unread_count: i32::from(unread),
+            unread_mentions_count: i32::from(unread && message.mentioned),
+            unread_reactions_count: 0,
+            notify_settings: tl_types::types::PeerNotifySettings {
+                show_previews: None,
+                silent: None,
+                mute_until: None,
+                ios_sound: None,
+                android_sound: None,
+                other_sound: None,
+                stories_muted: None,
+                stories_hide_sender: None,
+                stories_ios_sound: None,
+                stories_android_sound: None,
+                stories_other_sound: None,
+            }
+            .into(),
+            pts: None,
+            draft: None,
+            folder_id: None,
+            ttl_period: None,
+        }
+    }
+
     fn save_chat(&self, chat: &Chat) -> Result<()> {
         match chat {
             Chat::User(usr) => self.save_user(usr),
@@ -135,36 +743,479 @@ impl Storage {
     }
 
     pub fn save_message(&self, message: &Message) -> Result<()> {
+        for chat in message.sender().into_iter().chain([message.chat()]) {
+            // Chats absent in the update are returned by grammers as stubs
+            // with empty names, don't overwrite good data with them.
+            if !chat.name().is_empty() {
+                self.save_chat(&chat)?;
+            }
+        }
+        self.save_raw_message(&message.raw)
+    }
+
+    fn save_raw_message(&self, raw: &tl_types::types::Message) -> Result<()> {
+        // Edited message replaces the old row, so drop the old text from
+        // the search index.
+        self.delete_from_search_index(peer_id(&raw.peer_id), raw.id)?;
         let statement = "INSERT OR REPLACE INTO messages(peer_id, message_id, date, data)
              VALUES (?, ?, ?, ?);";
         let mut cached_statement = self.connection.prepare_cached(statement)?;
-        let serialized = message.raw.to_bytes();
-        cached_statement.execute((
-            peer_id(&message.raw.peer_id),
-            message.id(),
-            message.date().timestamp(),
-            serialized,
-        ))?;
+        let serialized = raw.to_bytes();
+        cached_statement.execute((peer_id(&raw.peer_id), raw.id, raw.date, serialized))?;
+        if !raw.message.is_empty() {
+            let statement = "INSERT INTO messages_fts(rowid, text) VALUES (?, ?);";
+            let mut cached_statement = self.connection.prepare_cached(statement)?;
+            cached_statement.execute((self.connection.last_insert_rowid(), &raw.message))?;
+        }
+        if !matches!(raw.peer_id, tl_types::enums::Peer::Channel(_)) {
+            let statement = "INSERT OR REPLACE INTO message_peers(message_id, peer_id)
+                 VALUES (?, ?);";
+            let mut cached_statement = self.connection.prepare_cached(statement)?;
+            cached_statement.execute((raw.id, peer_id(&raw.peer_id)))?;
+        }
         Ok(())
     }
 
     pub fn delete_message(&self, message_deletion: &MessageDeletion) -> Result<()> {
-        if let Some(channel_id) = message_deletion.channel_id() {
-            let statement = "DELETE FROM messages WHERE peer_id = ? AND message_id = ?";
-            let mut cached_statement = self.connection.prepare_cached(statement)?;
-            for msg_id in message_deletion.messages() {
-                cached_statement.execute((channel_id, msg_id))?;
+        self.delete_messages(message_deletion.channel_id(), message_deletion.messages())
+    }
+
+    fn delete_from_search_index(&self, peer_id: i64, message_id: i32) -> Result<()> {
+        let statement = "DELETE FROM messages_fts WHERE rowid IN
+            (SELECT rowid FROM messages WHERE peer_id = ? AND message_id = ?);";
+        let mut cached_statement = self.connection.prepare_cached(statement)?;
+        cached_statement.execute((peer_id, message_id))?;
+        Ok(())
+    }
+
+    fn delete_stored_message(&self, peer_id: i64, message_id: i32) -> Result<()> {
+        let raw = self.select_raw_message(peer_id, message_id)?;
+        self.delete_from_search_index(peer_id, message_id)?;
+        let statement = "DELETE FROM messages WHERE peer_id = ? AND message_id = ?";
+        let mut cached_statement = self.connection.prepare_cached(statement)?;
+        cached_statement.execute((peer_id, message_id))?;
+        if let Some(raw) = raw {
+            self.remove_raw_message_from_dialog(&raw)?;
+        }
+        Ok(())
+    }
+
+    // Moves top message of the dialog to the newest stored one and
+    // uncounts the message, if it was unread.
+    fn remove_raw_message_from_dialog(&self, message: &tl_types::types::Message) -> Result<()> {
+        let mut select_stmt = self
+            .connection
+            .prepare_cached("SELECT MAX(message_id) FROM messages WHERE peer_id = ?;")?;
+        let newest_id = select_stmt.query_row([peer_id(&message.peer_id)], |r| {
+            r.get::<usize, Option<i32>>(0)
+        })?;
+        self.update_dialog(&message.peer_id, |dialog| {
+            if !message.out
+                && message.id > dialog.read_inbox_max_id
+                && message.id <= dialog.top_message
+            {
+                dialog.unread_count = std::cmp::max(dialog.unread_count - 1, 0);
+                if message.mentioned {
+                    dialog.unread_mentions_count =
+                        std::cmp::max(dialog.unread_mentions_count - 1, 0);
+                }
+            }
+            if message.id == dialog.top_message {
+                dialog.top_message = newest_id.unwrap_or(0);
+            }
+        })?;
+        Ok(())
+    }
+
+    fn select_raw_message(
+        &self,
+        peer_id: i64,
+        message_id: i32,
+    ) -> Result<Option<tl_types::types::Message>> {
+        let mut select_stmt = self
+            .connection
+            .prepare_cached("SELECT data FROM messages WHERE peer_id = ? AND message_id = ?;")?;
+        let mut rows = select_stmt.query((peer_id, message_id))?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let data = row.get::<usize, Vec<u8>>(0)?;
+        Ok(Some(tl_types::types::Message::deserialize(
+            &mut Cursor::from_slice(&data),
+        )?))
+    }
+
+    /// Returns date of the top message of the dialog, if it is stored.
+    pub fn select_top_message_date(
+        &self,
+        dialog: &tl_types::types::Dialog,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let raw = self.select_raw_message(peer_id(&dialog.peer), dialog.top_message)?;
+        Ok(raw.and_then(|raw| DateTime::from_timestamp(i64::from(raw.date), 0)))
+    }
+
+    /// Deletes messages of the channel, or, if `channel_id` is None,
+    /// messages of private chats and basic groups.
+    pub fn delete_messages(&self, channel_id: Option<i64>, message_ids: &[i32]) -> Result<()> {
+        if let Some(channel_id) = channel_id {
+            let peer_id = peer_id(&tl_types::types::PeerChannel { channel_id }.into());
+            for msg_id in message_ids {
+                self.delete_stored_message(peer_id, *msg_id)?;
             }
         } else {
-            let statement = "DELETE FROM messages WHERE message_id = ?";
-            let mut cached_statement = self.connection.prepare_cached(statement)?;
-            for msg_id in message_deletion.messages() {
-                cached_statement.execute([msg_id])?;
+            // Deletions outside of channels carry only message IDs, find
+            // peers of the messages.
+            let mut select_stmt = self
+                .connection
+                .prepare_cached("SELECT peer_id FROM message_peers WHERE message_id = ?;")?;
+            let mut delete_peer_stmt = self
+                .connection
+                .prepare_cached("DELETE FROM message_peers WHERE message_id = ?;")?;
+            for msg_id in message_ids {
+                let mut rows = select_stmt.query([msg_id])?;
+                if let Some(row) = rows.next()? {
+                    let peer_id = row.get::<usize, i64>(0)?;
+                    self.delete_stored_message(peer_id, *msg_id)?;
+                    delete_peer_stmt.execute([msg_id])?;
+                } else {
+                    log::info!("Deleted message {} is not stored", msg_id);
+                }
             }
         }
         Ok(())
     }
 
+    /// Changes text of the stored message, edited by user. Returns false if
+    /// the message is not stored.
+    pub fn edit_message_text(
+        &self,
+        peer_id: i64,
+        message_id: i32,
+        text: &str,
+        edit_date: i32,
+    ) -> Result<bool> {
+        let Some(mut raw) = self.select_raw_message(peer_id, message_id)? else {
+            return Ok(false);
+        };
+        raw.message = text.to_string();
+        // Entities refer to the old text.
+        raw.entities = None;
+        raw.edit_date = Some(edit_date);
+        self.save_raw_message(&raw)?;
+        Ok(true)
+    }
+
+    /// Adds message to send with a new random ID and returns its local ID.
+    pub fn add_outbox_message(
+        &self,
+        chat: &PackedChat,
+        text: &str,
+        reply_to: Option<i32>,
+        date: i32,
+    ) -> Result<i64> {
+        let mut insert_stmt = self.connection.prepare_cached(
+            "INSERT INTO outbox(peer_id, chat, text, reply_to, date, failed, random_id, in_flight)
+             VALUES (?, ?, ?, ?, ?, 0, random(), 0);",
+        )?;
+        insert_stmt.execute((chat_peer_id(chat), chat.to_bytes(), text, reply_to, date))?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    /// Returns outbox messages to the peer, oldest first.
+    pub fn select_outbox_messages(&self, peer_id: i64) -> Result<Vec<OutboxMessage>> {
+        self.query_outbox_messages(
+            "SELECT local_id, chat, text, date, failed, reply_to, random_id FROM outbox
+             WHERE peer_id = ? ORDER BY local_id;",
+            [peer_id],
+        )
+    }
+
+    /// Returns outbox messages to all peers waiting to be sent, oldest first.
+    pub fn select_pending_outbox_messages(&self) -> Result<Vec<OutboxMessage>> {
+        self.query_outbox_messages(
+            "SELECT local_id, chat, text, date, failed, reply_to, random_id FROM outbox
+             WHERE failed = 0 ORDER BY local_id;",
+            [],
+        )
+    }
+
+    fn query_outbox_messages<P: rusqlite::Params>(
+        &self,
+        statement: &str,
+        params: P,
+    ) -> Result<Vec<OutboxMessage>> {
+        let mut select_stmt = self.connection.prepare_cached(statement)?;
+        let mut rows = select_stmt.query(params)?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            let chat_data = row.get::<usize, Vec<u8>>(1)?;
+            let chat = PackedChat::from_bytes(&chat_data)
+                .map_err(|_| eyre!("DB damaged, bad outbox chat"))?;
+            let state = if row.get::<usize, bool>(4)? {
+                OutboxState::Failed
+            } else {
+                OutboxState::Pending
+            };
+            result.push(OutboxMessage {
+                local_id: row.get(0)?,
+                chat,
+                text: row.get(2)?,
+                reply_to: row.get(5)?,
+                date: row.get(3)?,
+                state,
+                random_id: row.get(6)?,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Sets state of outbox message, which server did not accept.
+    pub fn set_outbox_state(&self, local_id: i64, state: OutboxState) -> Result<()> {
+        let mut update_stmt = self
+            .connection
+            .prepare_cached("UPDATE outbox SET failed = ?, in_flight = 0 WHERE local_id = ?;")?;
+        update_stmt.execute((state == OutboxState::Failed, local_id))?;
+        Ok(())
+    }
+
+    /// Marks outbox message as sent, but not confirmed by server yet.
+    pub fn set_outbox_in_flight(&self, local_id: i64) -> Result<()> {
+        let mut update_stmt = self
+            .connection
+            .prepare_cached("UPDATE outbox SET in_flight = 1 WHERE local_id = ?;")?;
+        update_stmt.execute([local_id])?;
+        Ok(())
+    }
+
+    /// Makes failed outbox messages to the peer pending again.
+    pub fn retry_failed_outbox_messages(&self, peer_id: i64) -> Result<()> {
+        let mut update_stmt = self
+            .connection
+            .prepare_cached("UPDATE outbox SET failed = 0 WHERE peer_id = ?;")?;
+        update_stmt.execute([peer_id])?;
+        Ok(())
+    }
+
+    /// Removes outbox message, that got its server ID.
+    pub fn delete_outbox_message(&self, local_id: i64) -> Result<()> {
+        let mut delete_stmt = self
+            .connection
+            .prepare_cached("DELETE FROM outbox WHERE local_id = ?;")?;
+        delete_stmt.execute([local_id])?;
+        Ok(())
+    }
+
+    /// Removes outbox message in flight, that server confirmed by its
+    /// random ID, as response to the sending request may be lost with the
+    /// connection, leaving the message pending. Returns false if there is
+    /// no such message.
+    pub fn reconcile_outbox(&self, random_id: i64) -> Result<bool> {
+        let mut delete_stmt = self
+            .connection
+            .prepare_cached("DELETE FROM outbox WHERE random_id = ? AND in_flight = 1;")?;
+        let deleted = delete_stmt.execute([random_id])?;
+        Ok(deleted > 0)
+    }
+
+    pub fn save_media_file(&self, media_key: &str, media_file: &MediaFile) -> Result<()> {
+        let mut insert_stmt = self.connection.prepare_cached(
+            "INSERT OR REPLACE INTO media_files(media_key, content_hash, file_name, size)
+             VALUES (?, ?, ?, ?);",
+        )?;
+        insert_stmt.execute((
+            media_key,
+            &media_file.content_hash,
+            &media_file.file_name,
+            media_file.size,
+        ))?;
+        Ok(())
+    }
+
+    pub fn select_media_file(&self, media_key: &str) -> Result<Option<MediaFile>> {
+        let mut select_stmt = self.connection.prepare_cached(
+            "SELECT content_hash, file_name, size FROM media_files WHERE media_key = ?;",
+        )?;
+        let mut rows = select_stmt.query([media_key])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        Ok(Some(MediaFile {
+            content_hash: row.get(0)?,
+            file_name: row.get(1)?,
+            size: row.get(2)?,
+        }))
+    }
+
+    /// Adds range of message IDs, fully fetched from server, merging it
+    /// with touching or overlapping ranges.
+    pub fn add_history_range(&self, peer_id: i64, first_id: i32, last_id: i32) -> Result<()> {
+        let mut select_stmt = self.connection.prepare_cached(
+            "SELECT MIN(first_id), MAX(last_id) FROM history_ranges
+             WHERE peer_id = ? AND first_id <= ? + 1 AND last_id + 1 >= ?;",
+        )?;
+        let (min_first_id, max_last_id) =
+            select_stmt.query_row((peer_id, last_id, first_id), |r| {
+                Ok((
+                    r.get::<usize, Option<i32>>(0)?,
+                    r.get::<usize, Option<i32>>(1)?,
+                ))
+            })?;
+        let merged_first_id = min_first_id.map_or(first_id, |id| std::cmp::min(id, first_id));
+        let merged_last_id = max_last_id.map_or(last_id, |id| std::cmp::max(id, last_id));
+        let mut delete_stmt = self.connection.prepare_cached(
+            "DELETE FROM history_ranges
+             WHERE peer_id = ? AND first_id >= ? AND last_id <= ?;",
+        )?;
+        delete_stmt.execute((peer_id, merged_first_id, merged_last_id))?;
+        let mut insert_stmt = self.connection.prepare_cached(
+            "INSERT INTO history_ranges(peer_id, first_id, last_id) VALUES (?, ?, ?);",
+        )?;
+        insert_stmt.execute((peer_id, merged_first_id, merged_last_id))?;
+        Ok(())
+    }
+
+    /// Extends the newest history range of the peer to include the message,
+    /// received as a live update, if the message directly follows the range.
+    /// Otherwise messages between them may be missed, and the range is left
+    /// for the next history refresh to extend.
+    pub fn extend_newest_history_range(&self, peer_id: i64, message_id: i32) -> Result<bool> {
+        let mut update_stmt = self.connection.prepare_cached(
+            "UPDATE history_ranges SET last_id = ?
+             WHERE peer_id = ? AND last_id + 1 = ? AND last_id =
+               (SELECT MAX(last_id) FROM history_ranges WHERE peer_id = ?);",
+        )?;
+        let updated = update_stmt.execute((message_id, peer_id, message_id, peer_id))?;
+        Ok(updated > 0)
+    }
+
+    /// Returns (first_id, last_id) of the newest history range of the peer.
+    pub fn newest_history_range(&self, peer_id: i64) -> Result<Option<(i32, i32)>> {
+        let mut select_stmt = self.connection.prepare_cached(
+            "SELECT first_id, last_id FROM history_ranges
+             WHERE peer_id = ? ORDER BY last_id DESC LIMIT 1;",
+        )?;
+        let mut rows = select_stmt.query([peer_id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some((row.get(0)?, row.get(1)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns messages of the peer matching the query. Senders and chats
+    /// of the messages are resolved from stored users, groups and channels.
+    pub fn select_messages(&self, query: &MessagesQuery) -> Result<Vec<StoredMessage>> {
+        let (id_condition, bound_id) = match query.range {
+            MessagesRange::All => ("", 0),
+            MessagesRange::Before(id) => ("AND message_id < ?2", id),
+            MessagesRange::After(id) => ("AND message_id > ?2", id),
+        };
+        let order = match query.order {
+            MessagesOrder::IdAscending => "message_id ASC",
+            MessagesOrder::IdDescending => "message_id DESC",
+            MessagesOrder::DateAscending => "date ASC, message_id ASC",
+            MessagesOrder::DateDescending => "date DESC, message_id DESC",
+        };
+        let statement = format!(
+            "SELECT data FROM messages WHERE peer_id = ?1 {} ORDER BY {} LIMIT ?3;",
+            id_condition, order
+        );
+        let mut select_stmt = self.connection.prepare_cached(&statement)?;
+        // Parameters are numbered, so unused ?2 is still bound without errors.
+        let mut rows = select_stmt.query((query.peer_id, bound_id, query.limit))?;
+        let mut raw_messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            let data = row.get::<usize, Vec<u8>>(0)?;
+            let raw = tl_types::types::Message::deserialize(&mut Cursor::from_slice(&data))?;
+            raw_messages.push(raw);
+        }
+        self.make_messages(raw_messages)
+    }
+
+    /// Returns up to `limit` messages from all chats containing all words
+    /// of `text` (or words starting with them), newest first.
+    pub fn search_messages(&self, text: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        let raw_messages = self.search_raw_messages(text, limit)?;
+        self.make_messages(raw_messages)
+    }
+
+    fn search_raw_messages(
+        &self,
+        text: &str,
+        limit: usize,
+    ) -> Result<Vec<tl_types::types::Message>> {
+        // Quote every word, so FTS5 query syntax in user input is ignored.
+        let fts_query = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if fts_query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut select_stmt = self.connection.prepare_cached(
+            "SELECT messages.data FROM messages_fts
+             JOIN messages ON messages.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?
+             ORDER BY messages.date DESC LIMIT ?;",
+        )?;
+        let mut rows = select_stmt.query((fts_query, limit))?;
+        let mut raw_messages = Vec::new();
+        while let Some(row) = rows.next()? {
+            let data = row.get::<usize, Vec<u8>>(0)?;
+            let raw = tl_types::types::Message::deserialize(&mut Cursor::from_slice(&data))?;
+            raw_messages.push(raw);
+        }
+        Ok(raw_messages)
+    }
+
+    fn make_messages(
+        &self,
+        raw_messages: Vec<tl_types::types::Message>,
+    ) -> Result<Vec<StoredMessage>> {
+        let chats = self.make_chat_map(&raw_messages)?;
+        let result = raw_messages
+            .into_iter()
+            .map(|raw| StoredMessage {
+                raw,
+                chats: chats.clone(),
+            })
+            .collect();
+        Ok(result)
+    }
+
+    // Builds map with all known senders and chats of the messages, so
+    // StoredMessage::sender() and StoredMessage::chat() return full entities.
+    fn make_chat_map(&self, raw_messages: &[tl_types::types::Message]) -> Result<Arc<ChatMap>> {
+        let mut seen_peers = Vec::<&tl_types::enums::Peer>::new();
+        let mut users = Vec::new();
+        let mut chats = Vec::new();
+        let peers = raw_messages
+            .iter()
+            .flat_map(|raw| std::iter::once(&raw.peer_id).chain(raw.from_id.iter()));
+        for peer in peers {
+            if seen_peers.contains(&peer) {
+                continue;
+            }
+            seen_peers.push(peer);
+            match self.load_chat(peer.clone()) {
+                Ok(chat) => push_raw_chat(chat, &mut users, &mut chats),
+                Err(e) if Self::is_not_found(&e) => {
+                    // Entity not cached yet, StoredMessage makes a stub for it.
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ChatMap::new(users, chats))
+    }
+
+    fn is_not_found(error: &eyre::Report) -> bool {
+        matches!(
+            error.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::QueryReturnedNoRows)
+        )
+    }
+
     fn load_chat(&self, peer: tl_types::enums::Peer) -> Result<Chat> {
         // NOTE, that ID sequences for users, chats and channels, overlap
         // (that stated by Telegram API documentation),
@@ -179,8 +1230,16 @@ impl Storage {
                 Ok(Chat::Group(result))
             }
             tl_types::enums::Peer::Channel(peer_channel) => {
-                let result = self.load_channel(peer_channel)?;
-                Ok(Chat::Channel(result))
+                // Megagroups are channels for Telegram, but grammers
+                // represents them as groups, so they are in "groups" table.
+                match self.load_channel(peer_channel.clone()) {
+                    Ok(result) => Ok(Chat::Channel(result)),
+                    Err(e) if Self::is_not_found(&e) => {
+                        let result = self.load_group_by_id(peer_channel.channel_id)?;
+                        Ok(Chat::Group(result))
+                    }
+                    Err(e) => Err(e),
+                }
             }
         }
     }
@@ -195,10 +1254,14 @@ impl Storage {
     }
 
     fn load_group(&self, group: tl_types::types::PeerChat) -> Result<Group> {
+        self.load_group_by_id(group.chat_id)
+    }
+
+    fn load_group_by_id(&self, id: i64) -> Result<Group> {
         let mut select_stmt = self
             .connection
             .prepare_cached("SELECT data FROM groups WHERE id=?;")?;
-        let data = select_stmt.query_row([group.chat_id], |r| r.get::<usize, Vec<u8>>(0))?;
+        let data = select_stmt.query_row([id], |r| r.get::<usize, Vec<u8>>(0))?;
         let raw = tl_types::enums::Chat::deserialize(&mut Cursor::from_slice(&data))?;
         Ok(Group::from_raw(raw))
     }
@@ -236,4 +1299,710 @@ impl Storage {
         cached_statement.execute([serialized])?;
         Ok(())
     }
+
+    // Update state table has only one row too.
+    const UPDATE_STATE_ROW_ID: i32 = 1;
+
+    /// Returns update state saved by `save_update_state`, or None if it was
+    /// never saved, e.g. right after sign in.
+    pub fn load_update_state(&self) -> Result<Option<UpdateState>> {
+        let statement = format!(
+            "SELECT pts, qts, seq, date, channels FROM update_state WHERE id={};",
+            Self::UPDATE_STATE_ROW_ID
+        );
+        let mut cached_statement = self.connection.prepare_cached(&statement)?;
+        let mut rows = cached_statement.query(())?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        // Serialized state is these numbers followed by the channels.
+        let mut data = Vec::new();
+        for column in ["pts", "qts", "date", "seq"] {
+            row.get::<&str, i32>(column)?.serialize(&mut data);
+        }
+        data.extend(row.get::<&str, Vec<u8>>("channels")?);
+        Ok(Some(UpdateState::from_bytes(&data)?))
+    }
+
+    pub fn save_update_state(&self, state: &UpdateState) -> Result<()> {
+        let statement = format!(
+            "INSERT OR REPLACE INTO update_state(id, pts, qts, seq, date, channels)
+             VALUES ({}, ?, ?, ?, ?, ?);",
+            Self::UPDATE_STATE_ROW_ID
+        );
+        let mut cached_statement = self.connection.prepare_cached(&statement)?;
+        cached_statement.execute((
+            state.pts,
+            state.qts,
+            state.seq,
+            state.date,
+            state.channels.to_bytes(),
+        ))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_storage() -> Storage {
+        Storage::new(std::path::Path::new(":memory:")).unwrap()
+    }
+
+    fn make_raw_message(peer_id: tl_types::enums::Peer, id: i32) -> tl_types::types::Message {
+        tl_types::types::Message {
+            out: false,
+            mentioned: false,
+            media_unread: false,
+            silent: false,
+            post: false,
+            from_scheduled: false,
+            legacy: false,
+            edit_hide: false,
+            pinned: false,
+            noforwards: false,
+            invert_media: false,
+            offline: false,
+            id,
+            from_id: None,
+            from_boosts_applied: None,
+            peer_id,
+            saved_peer_id: None,
+            fwd_from: None,
+            via_bot_id: None,
+            via_business_bot_id: None,
+            reply_to: None,
+            date: 1700000000,
+            message: format!("Message {}", id),
+            media: None,
+            reply_markup: None,
+            entities: None,
+            views: None,
+            forwards: None,
+            replies: None,
+            edit_date: None,
+            post_author: None,
+            grouped_id: None,
+            reactions: None,
+            restriction_reason: None,
+            ttl_period: None,
+            quick_reply_shortcut_id: None,
+            effect: None,
+            factcheck: None,
+        }
+    }
+
+    fn user_peer(user_id: i64) -> tl_types::enums::Peer {
+        tl_types::types::PeerUser { user_id }.into()
+    }
+
+    fn group_peer(chat_id: i64) -> tl_types::enums::Peer {
+        tl_types::types::PeerChat { chat_id }.into()
+    }
+
+    fn channel_peer(channel_id: i64) -> tl_types::enums::Peer {
+        tl_types::types::PeerChannel { channel_id }.into()
+    }
+
+    fn has_message(storage: &Storage, peer: &tl_types::enums::Peer, message_id: i32) -> bool {
+        let count = storage
+            .connection
+            .query_row(
+                "SELECT COUNT(*) FROM messages WHERE peer_id = ? AND message_id = ?;",
+                (peer_id(peer), message_id),
+                |r| r.get::<usize, i64>(0),
+            )
+            .unwrap();
+        count > 0
+    }
+
+    #[test]
+    fn delete_without_channel_removes_only_mapped_message() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(group_peer(20), 2))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(30), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(40), 2))
+            .unwrap();
+
+        storage.delete_messages(None, &[1, 2]).unwrap();
+
+        assert!(!has_message(&storage, &user_peer(10), 1));
+        assert!(!has_message(&storage, &group_peer(20), 2));
+        assert!(has_message(&storage, &channel_peer(30), 1));
+        assert!(has_message(&storage, &channel_peer(40), 2));
+    }
+
+    #[test]
+    fn delete_with_channel_removes_only_channel_message() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(30), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(40), 1))
+            .unwrap();
+
+        storage.delete_messages(Some(30), &[1]).unwrap();
+
+        assert!(has_message(&storage, &user_peer(10), 1));
+        assert!(!has_message(&storage, &channel_peer(30), 1));
+        assert!(has_message(&storage, &channel_peer(40), 1));
+    }
+
+    #[test]
+    fn delete_keeps_messages_of_peers_with_equal_ids() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(group_peer(10), 2))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(10), 2))
+            .unwrap();
+
+        storage.delete_messages(None, &[1]).unwrap();
+        assert!(!has_message(&storage, &user_peer(10), 1));
+        assert!(has_message(&storage, &group_peer(10), 2));
+        assert!(has_message(&storage, &channel_peer(10), 1));
+
+        storage.delete_messages(Some(10), &[2]).unwrap();
+        assert!(has_message(&storage, &group_peer(10), 2));
+        assert!(!has_message(&storage, &channel_peer(10), 2));
+    }
+
+    #[test]
+    fn bare_peer_ids_of_old_databases_are_marked() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(group_peer(20), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(30), 1))
+            .unwrap();
+        storage
+            .add_history_range(peer_id(&group_peer(20)), 0, 1)
+            .unwrap();
+        // Database made before peer IDs were marked.
+        storage
+            .connection
+            .execute(
+                "UPDATE messages SET peer_id = -peer_id WHERE peer_id > -?;",
+                [CHANNEL_ID_MARK],
+            )
+            .unwrap();
+        storage
+            .connection
+            .execute(
+                "UPDATE messages SET peer_id = -peer_id - ? WHERE peer_id < -?;",
+                (CHANNEL_ID_MARK, CHANNEL_ID_MARK),
+            )
+            .unwrap();
+        assert!(has_message(&storage, &user_peer(20), 1));
+        storage.mark_peer_ids().unwrap();
+        storage.rebuild_message_peers().unwrap();
+
+        assert!(!has_message(&storage, &user_peer(20), 1));
+        assert!(has_message(&storage, &group_peer(20), 1));
+        assert!(has_message(&storage, &channel_peer(30), 1));
+        assert!(history_ranges(&storage, peer_id(&group_peer(20))).is_empty());
+        storage.delete_messages(None, &[1]).unwrap();
+        assert!(!has_message(&storage, &group_peer(20), 1));
+        assert!(has_message(&storage, &channel_peer(30), 1));
+    }
+
+    #[test]
+    fn message_peers_are_restored_for_old_databases() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(30), 2))
+            .unwrap();
+        // Database made before messages were mapped to peers.
+        storage
+            .connection
+            .execute("DROP TABLE message_peers;", ())
+            .unwrap();
+        assert!(Storage::ensure_message_peers_table(&storage.connection).unwrap());
+        storage.rebuild_message_peers().unwrap();
+
+        storage.delete_messages(None, &[1, 2]).unwrap();
+
+        assert!(!has_message(&storage, &user_peer(10), 1));
+        assert!(has_message(&storage, &channel_peer(30), 2));
+    }
+
+    fn search_ids(storage: &Storage, text: &str) -> Vec<i32> {
+        storage
+            .search_raw_messages(text, 100)
+            .unwrap()
+            .into_iter()
+            .map(|raw| raw.id)
+            .collect()
+    }
+
+    #[test]
+    fn search_finds_saved_messages_by_word_prefix() {
+        let storage = make_storage();
+        let mut first = make_raw_message(user_peer(10), 1);
+        first.message = "See https://example.com/docs".to_string();
+        let mut second = make_raw_message(channel_peer(30), 2);
+        second.message = "Release notes".to_string();
+        storage.save_raw_message(&first).unwrap();
+        storage.save_raw_message(&second).unwrap();
+
+        assert_eq!(search_ids(&storage, "example"), vec![1]);
+        assert_eq!(search_ids(&storage, "rel NOT"), vec![2]);
+        assert!(search_ids(&storage, "missing").is_empty());
+        assert!(search_ids(&storage, "  ").is_empty());
+    }
+
+    #[test]
+    fn search_index_follows_edits_and_deletions() {
+        let storage = make_storage();
+        let mut message = make_raw_message(channel_peer(30), 1);
+        message.message = "old text".to_string();
+        storage.save_raw_message(&message).unwrap();
+        message.message = "new text".to_string();
+        storage.save_raw_message(&message).unwrap();
+
+        assert!(search_ids(&storage, "old").is_empty());
+        assert_eq!(search_ids(&storage, "new"), vec![1]);
+
+        storage.delete_messages(Some(30), &[1]).unwrap();
+        assert!(search_ids(&storage, "new").is_empty());
+    }
+
+    #[test]
+    fn delete_unknown_message_keeps_others() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        storage
+            .save_raw_message(&make_raw_message(channel_peer(30), 5))
+            .unwrap();
+
+        storage.delete_messages(None, &[5]).unwrap();
+
+        assert!(has_message(&storage, &user_peer(10), 1));
+        assert!(has_message(&storage, &channel_peer(30), 5));
+    }
+
+    #[test]
+    fn selected_messages_have_stub_chats_when_not_stored() {
+        let storage = make_storage();
+        storage
+            .save_raw_message(&make_raw_message(user_peer(10), 1))
+            .unwrap();
+        let mut outgoing = make_raw_message(user_peer(10), 2);
+        outgoing.out = true;
+        storage.save_raw_message(&outgoing).unwrap();
+
+        let query = MessagesQuery {
+            peer_id: 10,
+            range: MessagesRange::All,
+            order: MessagesOrder::IdAscending,
+            limit: 10,
+        };
+        let messages = storage.select_messages(&query).unwrap();
+        assert_eq!(messages.len(), 2);
+        let chat = messages[0].chat();
+        assert_eq!(chat.id(), 10);
+        assert!(chat.name().is_empty());
+        // Sender of incoming private message is the chat itself.
+        assert_eq!(messages[0].sender().map(|sender| sender.id()), Some(10));
+        assert!(messages[1].sender().is_none());
+    }
+
+    #[test]
+    fn messages_are_selected_by_date_order() {
+        let storage = make_storage();
+        // Imported messages keep their original dates.
+        for (id, date) in [(1, 1700000300), (2, 1700000100), (3, 1700000200)] {
+            let mut message = make_raw_message(user_peer(10), id);
+            message.date = date;
+            storage.save_raw_message(&message).unwrap();
+        }
+        let select = |range, order| {
+            let query = MessagesQuery {
+                peer_id: 10,
+                range,
+                order,
+                limit: 10,
+            };
+            storage
+                .select_messages(&query)
+                .unwrap()
+                .iter()
+                .map(|message| message.id())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            select(MessagesRange::All, MessagesOrder::DateAscending),
+            vec![2, 3, 1]
+        );
+        assert_eq!(
+            select(MessagesRange::All, MessagesOrder::DateDescending),
+            vec![1, 3, 2]
+        );
+        assert_eq!(
+            select(MessagesRange::Before(3), MessagesOrder::DateAscending),
+            vec![2, 1]
+        );
+        assert_eq!(
+            select(MessagesRange::All, MessagesOrder::IdAscending),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn stub_channels_are_broadcast_only_for_posts() {
+        let storage = make_storage();
+        let mut post = make_raw_message(channel_peer(30), 1);
+        post.post = true;
+        storage.save_raw_message(&post).unwrap();
+        let mut group_message = make_raw_message(channel_peer(40), 1);
+        group_message.from_id = Some(channel_peer(30));
+        storage.save_raw_message(&group_message).unwrap();
+
+        let select = |peer_id| {
+            let query = MessagesQuery {
+                peer_id,
+                range: MessagesRange::All,
+                order: MessagesOrder::IdAscending,
+                limit: 10,
+            };
+            storage.select_messages(&query).unwrap().remove(0)
+        };
+        assert!(matches!(
+            select(peer_id(&channel_peer(30))).chat(),
+            Chat::Channel(_)
+        ));
+        let group_message = select(peer_id(&channel_peer(40)));
+        assert!(matches!(group_message.chat(), Chat::Group(_)));
+        assert!(matches!(group_message.sender(), Some(Chat::Channel(_))));
+    }
+
+    #[test]
+    fn edited_text_replaces_stored_and_indexed_text() {
+        let storage = make_storage();
+        let mut message = make_raw_message(user_peer(10), 1);
+        message.message = "typo".to_string();
+        storage.save_raw_message(&message).unwrap();
+
+        assert!(storage
+            .edit_message_text(10, 1, "fixed", 1700000100)
+            .unwrap());
+        assert!(!storage
+            .edit_message_text(10, 2, "missing", 1700000100)
+            .unwrap());
+        let raw = storage.search_raw_messages("fixed", 10).unwrap();
+        assert_eq!(raw.len(), 1);
+        assert_eq!(raw[0].edit_date, Some(1700000100));
+        assert!(search_ids(&storage, "typo").is_empty());
+    }
+
+    fn user_chat(user_id: i64) -> PackedChat {
+        PackedChat {
+            ty: grammers_client::session::PackedType::User,
+            id: user_id,
+            access_hash: Some(1),
+        }
+    }
+
+    fn outbox_texts(storage: &Storage, peer_id: i64) -> Vec<(String, OutboxState)> {
+        storage
+            .select_outbox_messages(peer_id)
+            .unwrap()
+            .into_iter()
+            .map(|message| (message.text, message.state))
+            .collect()
+    }
+
+    #[test]
+    fn outbox_keeps_order_and_state() {
+        let storage = make_storage();
+        let first = storage
+            .add_outbox_message(&user_chat(10), "first", None, 1700000000)
+            .unwrap();
+        storage
+            .add_outbox_message(&user_chat(10), "second", None, 1700000001)
+            .unwrap();
+        storage
+            .add_outbox_message(&user_chat(20), "other", None, 1700000002)
+            .unwrap();
+        storage
+            .set_outbox_state(first, OutboxState::Failed)
+            .unwrap();
+
+        assert_eq!(
+            outbox_texts(&storage, 10),
+            vec![
+                ("first".to_string(), OutboxState::Failed),
+                ("second".to_string(), OutboxState::Pending)
+            ]
+        );
+        let pending: Vec<_> = storage
+            .select_pending_outbox_messages()
+            .unwrap()
+            .into_iter()
+            .map(|message| (message.chat.id, message.text))
+            .collect();
+        assert_eq!(
+            pending,
+            vec![(10, "second".to_string()), (20, "other".to_string())]
+        );
+
+        storage.retry_failed_outbox_messages(10).unwrap();
+        assert_eq!(storage.select_pending_outbox_messages().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn outbox_is_reconciled_by_random_id_of_message_in_flight() {
+        let storage = make_storage();
+        let first = storage
+            .add_outbox_message(&user_chat(10), "hello", None, 1700000000)
+            .unwrap();
+        storage
+            .add_outbox_message(&user_chat(10), "hello", None, 1700000001)
+            .unwrap();
+        let messages = storage.select_outbox_messages(10).unwrap();
+        let (first_random_id, second_random_id) = (messages[0].random_id, messages[1].random_id);
+        assert_ne!(first_random_id, second_random_id);
+
+        // Pending messages are not sent yet, so server can't confirm them.
+        assert!(!storage.reconcile_outbox(first_random_id).unwrap());
+        storage.set_outbox_in_flight(first).unwrap();
+        assert!(!storage.reconcile_outbox(second_random_id).unwrap());
+        assert!(storage.reconcile_outbox(first_random_id).unwrap());
+
+        let left = storage.select_outbox_messages(10).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].random_id, second_random_id);
+        assert_eq!(left[0].state, OutboxState::Pending);
+    }
+
+    fn save_raw_dialog(storage: &Storage, id: i64, dialog: tl_types::types::Dialog) {
+        storage
+            .save_generic("dialogs", id, &tl_types::enums::Dialog::Dialog(dialog))
+            .unwrap();
+    }
+
+    fn stored_dialog(storage: &Storage, peer: tl_types::enums::Peer) -> tl_types::types::Dialog {
+        storage.select_raw_dialog(&peer).unwrap().unwrap()
+    }
+
+    #[test]
+    fn new_messages_update_dialog_counters() {
+        let storage = make_storage();
+        let mut dialog = Storage::new_raw_dialog(&make_raw_message(user_peer(10), 5), false);
+        dialog.read_inbox_max_id = 5;
+        dialog.unread_count = 0;
+        save_raw_dialog(&storage, 10, dialog);
+
+        let mut mention = make_raw_message(user_peer(10), 6);
+        mention.mentioned = true;
+        assert!(storage.add_raw_message_to_dialog(&mention, false).unwrap());
+        let mut outgoing = make_raw_message(user_peer(10), 7);
+        outgoing.out = true;
+        assert!(storage.add_raw_message_to_dialog(&outgoing, false).unwrap());
+        // Repeated and older messages are not counted.
+        assert!(storage.add_raw_message_to_dialog(&mention, false).unwrap());
+        assert!(storage
+            .add_raw_message_to_dialog(&make_raw_message(user_peer(10), 3), false)
+            .unwrap());
+
+        let dialog = stored_dialog(&storage, user_peer(10));
+        assert_eq!(dialog.top_message, 7);
+        assert_eq!(dialog.unread_count, 1);
+        assert_eq!(dialog.unread_mentions_count, 1);
+
+        // Message arrived to the open chat is read at once.
+        let mut read = make_raw_message(user_peer(10), 8);
+        read.mentioned = true;
+        assert!(storage.add_raw_message_to_dialog(&read, true).unwrap());
+        let dialog = stored_dialog(&storage, user_peer(10));
+        assert_eq!(dialog.top_message, 8);
+        assert_eq!(dialog.read_inbox_max_id, 8);
+        assert_eq!(dialog.unread_count, 1);
+        assert_eq!(dialog.unread_mentions_count, 1);
+    }
+
+    #[test]
+    fn deleted_messages_update_dialog_counters() {
+        let storage = make_storage();
+        let mut dialog = Storage::new_raw_dialog(&make_raw_message(user_peer(10), 5), false);
+        dialog.read_inbox_max_id = 5;
+        dialog.unread_count = 0;
+        save_raw_dialog(&storage, 10, dialog);
+        for id in 5..=7 {
+            let mut message = make_raw_message(user_peer(10), id);
+            message.mentioned = id == 6;
+            storage.save_raw_message(&message).unwrap();
+            storage.add_raw_message_to_dialog(&message, false).unwrap();
+        }
+        assert_eq!(stored_dialog(&storage, user_peer(10)).unread_count, 2);
+
+        storage.delete_messages(None, &[7]).unwrap();
+        let dialog = stored_dialog(&storage, user_peer(10));
+        assert_eq!(dialog.top_message, 6);
+        assert_eq!(dialog.unread_count, 1);
+        assert_eq!(dialog.unread_mentions_count, 1);
+
+        storage.delete_messages(None, &[6]).unwrap();
+        let dialog = stored_dialog(&storage, user_peer(10));
+        assert_eq!(dialog.top_message, 5);
+        assert_eq!(dialog.unread_count, 0);
+        assert_eq!(dialog.unread_mentions_count, 0);
+    }
+
+    #[test]
+    fn update_dialog_finds_megagroup_by_channel_peer() {
+        let storage = make_storage();
+        // Megagroups are stored with IDs of groups.
+        save_raw_dialog(
+            &storage,
+            -30,
+            Storage::new_raw_dialog(&make_raw_message(channel_peer(30), 1), false),
+        );
+
+        let updated = storage
+            .update_dialog(&channel_peer(30), |dialog| dialog.pinned = true)
+            .unwrap();
+        assert!(updated);
+        assert!(stored_dialog(&storage, channel_peer(30)).pinned);
+        let updated = storage
+            .update_dialog(&channel_peer(40), |dialog| dialog.pinned = true)
+            .unwrap();
+        assert!(!updated);
+    }
+
+    fn history_ranges(storage: &Storage, peer_id: i64) -> Vec<(i32, i32)> {
+        let mut stmt = storage
+            .connection
+            .prepare(
+                "SELECT first_id, last_id FROM history_ranges
+                 WHERE peer_id = ? ORDER BY first_id;",
+            )
+            .unwrap();
+        stmt.query_map([peer_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn history_ranges_merge_when_overlapping() {
+        let storage = make_storage();
+        storage.add_history_range(1, 10, 20).unwrap();
+        storage.add_history_range(1, 15, 30).unwrap();
+        storage.add_history_range(1, 5, 12).unwrap();
+        assert_eq!(history_ranges(&storage, 1), vec![(5, 30)]);
+    }
+
+    #[test]
+    fn history_ranges_merge_when_touching() {
+        let storage = make_storage();
+        storage.add_history_range(1, 10, 20).unwrap();
+        storage.add_history_range(1, 21, 30).unwrap();
+        storage.add_history_range(1, 1, 9).unwrap();
+        assert_eq!(history_ranges(&storage, 1), vec![(1, 30)]);
+    }
+
+    #[test]
+    fn history_ranges_with_gap_are_kept_apart() {
+        let storage = make_storage();
+        storage.add_history_range(1, 10, 20).unwrap();
+        storage.add_history_range(1, 22, 30).unwrap();
+        // Other peers don't affect the ranges.
+        storage.add_history_range(2, 21, 21).unwrap();
+        assert_eq!(history_ranges(&storage, 1), vec![(10, 20), (22, 30)]);
+        // Range covering both of them replaces them.
+        storage.add_history_range(1, 5, 40).unwrap();
+        assert_eq!(history_ranges(&storage, 1), vec![(5, 40)]);
+        assert_eq!(history_ranges(&storage, 2), vec![(21, 21)]);
+    }
+
+    #[test]
+    fn newest_history_range_extends_only_with_adjacent_message() {
+        let storage = make_storage();
+        storage.add_history_range(1, 1, 9).unwrap();
+        storage.add_history_range(1, 20, 30).unwrap();
+        assert!(storage.extend_newest_history_range(1, 31).unwrap());
+        assert!(!storage.extend_newest_history_range(1, 40).unwrap());
+        assert!(!storage.extend_newest_history_range(1, 10).unwrap());
+        assert_eq!(history_ranges(&storage, 1), vec![(1, 9), (20, 31)]);
+        assert_eq!(storage.newest_history_range(1).unwrap(), Some((20, 31)));
+    }
+
+    #[test]
+    fn update_state_is_restored() {
+        let storage = make_storage();
+        assert!(storage.load_update_state().unwrap().is_none());
+        let state = UpdateState {
+            pts: 10,
+            qts: 20,
+            date: 1700000000,
+            seq: 30,
+            channels: Vec::new(),
+        };
+        storage.save_update_state(&state).unwrap();
+        assert_eq!(storage.load_update_state().unwrap(), Some(state));
+    }
+
+    #[test]
+    fn channel_pts_are_read_from_update_state() {
+        // Serialized state with channels 5 and 7, as grammers saves it.
+        let mut data = Vec::new();
+        for value in [10, 20, 1700000000, 30] {
+            i32::serialize(&value, &mut data);
+        }
+        0x1cb5c415u32.serialize(&mut data);
+        2i32.serialize(&mut data);
+        for (channel_id, pts) in [(5i64, 100), (7i64, 200)] {
+            3266377933u32.serialize(&mut data);
+            channel_id.serialize(&mut data);
+            pts.serialize(&mut data);
+        }
+        let state = UpdateState::from_bytes(&data).unwrap();
+        assert_eq!(channel_pts(&state), HashMap::from([(5, 100), (7, 200)]));
+    }
+
+    #[test]
+    fn media_files_are_found_by_key() {
+        let storage = make_storage();
+        assert_eq!(storage.select_media_file("photo1").unwrap(), None);
+        let media_file = MediaFile {
+            content_hash: "abc".to_string(),
+            file_name: "photo.jpg".to_string(),
+            size: 100,
+        };
+        storage.save_media_file("photo1", &media_file).unwrap();
+        storage.save_media_file("document2", &media_file).unwrap();
+
+        assert_eq!(
+            storage.select_media_file("photo1").unwrap(),
+            Some(media_file)
+        );
+        assert_eq!(storage.select_media_file("photo2").unwrap(), None);
+    }
 }
@@ -1,74 +1,383 @@
+use super::config::ApiCredentials;
 use super::storage::Storage;
+use super::ui::{Control, QrLoginControl, SignInControl};
 use color_eyre::Result;
-use grammers_client::{session::Session, Client, Config, SignInError};
-use std::io::{BufRead, Write};
-
-// TODO: At present most of the code here copied from grammers-client examples.
-// We should made sign-in process more user-friendly. Going to re-write UI
-// here with some Ratatui controls.
-pub struct TgClientBuilder {}
-
-const API_ID: i32 = match i32::from_str_radix(env!("TG_ID"), 10) {
-    Ok(v) => v,
-    Err(_) => {
-        panic!("Invalid TG_ID environment variable")
-    }
+use crossterm::event::{Event, EventStream};
+use eyre::eyre;
+use futures::StreamExt;
+use grammers_client::types::PasswordToken;
+use grammers_client::{
+    session::Session, Client, Config, InitParams, InvocationError, SignInError, Update,
 };
-const API_HASH: &str = env!("TG_HASH");
+use grammers_tl_types as tl_types;
+use ratatui::{DefaultTerminal, Frame};
+use std::time::Duration;
 
-impl TgClientBuilder {
-    fn prompt(message: &str) -> Result<String> {
-        let stdout = std::io::stdout();
-        let mut stdout = stdout.lock();
-        stdout.write_all(message.as_bytes())?;
-        stdout.flush()?;
+// Data center grammers-client connects to when session has no user yet.
+const DEFAULT_DC: i32 = 2;
+// Login token is re-exported at least that often, in case the update
+// about its acceptance is lost.
+const MAX_QR_TOKEN_WAIT: Duration = Duration::from_secs(30);
+/// Connection attempts, that take longer, are treated as failed.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
 
-        let stdin = std::io::stdin();
-        let mut stdin = stdin.lock();
+pub enum SignInResult {
+    SignedIn(Client),
+    // Connection failed, but the session is signed in already, so the
+    // cached data can be shown until the connection is restored.
+    Offline,
+    Cancelled,
+}
 
-        let mut line = String::new();
-        stdin.read_line(&mut line)?;
-        Ok(line)
-    }
+enum QrSignInResult {
+    SignedIn(Client),
+    PhoneRequested,
+    Cancelled,
+}
+
+enum QrWaitResult {
+    // Token expired or was accepted, it should be exported again.
+    TokenChanged,
+    PhoneRequested,
+    Cancelled,
+}
+
+// Connects to Telegram and, if the session is not authorized yet, asks
+// user to scan QR code or to enter phone number, code and password in the
+// terminal.
+pub struct TgClientBuilder<'a> {
+    // Title of sign in dialogs.
+    title: String,
+    api_credentials: &'a ApiCredentials,
+    terminal: &'a mut DefaultTerminal,
+    event_stream: EventStream,
+}
 
-    pub async fn make_signed_in_client(storage: &Storage) -> Result<Client> {
+impl<'a> TgClientBuilder<'a> {
+    /// `account_name` is shown to the user, if set.
+    pub async fn make_signed_in_client(
+        account_name: Option<&str>,
+        storage: &Storage,
+        api_credentials: &'a ApiCredentials,
+        terminal: &'a mut DefaultTerminal,
+    ) -> Result<SignInResult> {
         let session;
         if let Ok(sess) = storage.load_session() {
             session = sess;
         } else {
             session = Session::new();
         }
+        let signed_in_before = session.signed_in();
+        let connect = Self::connect(session, api_credentials);
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .unwrap_or_else(|_| Err(eyre!("Connection timed out")));
+        let mut client = match connected {
+            Ok(client) => client,
+            Err(e) if signed_in_before => {
+                log::warn!("Failed connect, starting offline; Error {:?}", e);
+                return Ok(SignInResult::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+        let authorized = match tokio::time::timeout(CONNECT_TIMEOUT, client.is_authorized()).await {
+            Ok(result) => result.map_err(eyre::Report::from),
+            Err(_) => Err(eyre!("Authorization check timed out")),
+        };
+        let authorized = match authorized {
+            Ok(authorized) => authorized,
+            Err(e) if signed_in_before => {
+                log::warn!(
+                    "Failed check authorization, starting offline; Error {:?}",
+                    e
+                );
+                return Ok(SignInResult::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !authorized {
+            let title = match account_name {
+                Some(name) => format!("Sign in to Telegram: {}", name),
+                None => "Sign in to Telegram".to_string(),
+            };
+            let mut builder = Self {
+                title,
+                api_credentials,
+                terminal,
+                event_stream: EventStream::new(),
+            };
+            match builder.sign_in(&client).await? {
+                Some(signed_in_client) => client = signed_in_client,
+                None => return Ok(SignInResult::Cancelled),
+            }
+            log::info!("Signed in!");
+        }
+        storage.save_session(client.session())?;
+        Ok(SignInResult::SignedIn(client))
+    }
+
+    /// Connects to Telegram with the session, without signing in.
+    pub async fn connect(session: Session, api_credentials: &ApiCredentials) -> Result<Client> {
         let client = Client::connect(Config {
             session,
-            api_id: API_ID,
-            api_hash: API_HASH.to_string(),
-            params: Default::default(),
+            api_id: api_credentials.api_id,
+            api_hash: api_credentials.api_hash.clone(),
+            params: InitParams {
+                // Fetch updates missed while the app was closed.
+                catch_up: true,
+                // Missed updates may be many, don't drop them.
+                update_queue_limit: None,
+                // Runtime retries requests after flood waits itself, without
+                // blocking other requests meanwhile.
+                flood_sleep_threshold: 0,
+                ..Default::default()
+            },
         })
         .await?;
+        Ok(client)
+    }
+
+    // Returns signed in client, or None if user cancelled sign in.
+    async fn sign_in(&mut self, client: &Client) -> Result<Option<Client>> {
+        match self.qr_sign_in(client).await? {
+            QrSignInResult::SignedIn(signed_in_client) => Ok(Some(signed_in_client)),
+            QrSignInResult::Cancelled => Ok(None),
+            QrSignInResult::PhoneRequested => {
+                if self.phone_sign_in(client).await? {
+                    Ok(Some(client.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    async fn qr_sign_in(&mut self, client: &Client) -> Result<QrSignInResult> {
+        let mut control = QrLoginControl::new(&self.title);
+        // Changes if the account lives in another data center.
+        let mut qr_client = client.clone();
+        let mut qr_dc_id = DEFAULT_DC;
+        let mut response = self.export_login_token(&qr_client).await;
+        loop {
+            match response {
+                Ok(tl_types::enums::auth::LoginToken::Token(token)) => {
+                    control.set_token(&token.token)?;
+                    match self
+                        .wait_for_qr_scan(&qr_client, &mut control, token.expires)
+                        .await?
+                    {
+                        QrWaitResult::TokenChanged => {}
+                        QrWaitResult::PhoneRequested => return Ok(QrSignInResult::PhoneRequested),
+                        QrWaitResult::Cancelled => return Ok(QrSignInResult::Cancelled),
+                    }
+                    response = self.export_login_token(&qr_client).await;
+                }
+                Ok(tl_types::enums::auth::LoginToken::MigrateTo(migrate_to)) => {
+                    log::info!("QR login migrates to DC {}", migrate_to.dc_id);
+                    qr_client = self.connect_to_dc(&qr_client, migrate_to.dc_id).await?;
+                    qr_dc_id = migrate_to.dc_id;
+                    response = qr_client
+                        .invoke(&tl_types::functions::auth::ImportLoginToken {
+                            token: migrate_to.token,
+                        })
+                        .await;
+                }
+                Ok(tl_types::enums::auth::LoginToken::Success(success)) => {
+                    let tl_types::enums::auth::Authorization::Authorization(authorization) =
+                        success.authorization
+                    else {
+                        return Err(eyre!(
+                            "No account for this login, sign up with official client first"
+                        ));
+                    };
+                    let user_id = authorization.user.id();
+                    qr_client.session().set_user(user_id, qr_dc_id, false);
+                    return self.reconnect_signed_in(&qr_client).await;
+                }
+                Err(e) if e.is("SESSION_PASSWORD_NEEDED") => {
+                    let password_token = Self::fetch_password_token(&qr_client).await?;
+                    if !self.check_password(&qr_client, password_token).await? {
+                        return Ok(QrSignInResult::Cancelled);
+                    }
+                    return self.reconnect_signed_in(&qr_client).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-        if !client.is_authorized().await? {
-            let phone = Self::prompt("Enter your phone number (international format): ")?;
-            let token = client.request_login_code(&phone).await?;
-            let code = Self::prompt("Enter the code you received: ")?;
-            let signed_in = client.sign_in(&token, &code).await;
-            match signed_in {
-                Err(SignInError::PasswordRequired(password_token)) => {
-                    // Note: this `prompt` method will echo the password in the console.
-                    //       Real code might want to use a better way to handle this.
-                    let hint = password_token.hint().unwrap_or("None");
-                    let prompt_message = format!("Enter the password (hint {}): ", &hint);
-                    let password = Self::prompt(prompt_message.as_str())?;
-
-                    client
-                        .check_password(password_token, password.trim())
-                        .await?;
+    async fn export_login_token(
+        &self,
+        client: &Client,
+    ) -> Result<tl_types::enums::auth::LoginToken, InvocationError> {
+        client
+            .invoke(&tl_types::functions::auth::ExportLoginToken {
+                api_id: self.api_credentials.api_id,
+                api_hash: self.api_credentials.api_hash.clone(),
+                except_ids: Vec::new(),
+            })
+            .await
+    }
+
+    // Returns client, that uses copy of the `client` session, but is
+    // connected to another data center.
+    async fn connect_to_dc(&self, client: &Client, dc_id: i32) -> Result<Client> {
+        let session = Session::load(&client.session().save())?;
+        // Client connects to the data center of the stored user. The
+        // real user ID is set when login succeeds.
+        session.set_user(0, dc_id, false);
+        Self::connect(session, self.api_credentials).await
+    }
+
+    // Client, that signed in by QR code, has no update state and info
+    // about self user yet, so connect again from the saved session.
+    async fn reconnect_signed_in(&self, client: &Client) -> Result<QrSignInResult> {
+        let session = Session::load(&client.session().save())?;
+        let signed_in_client = Self::connect(session, self.api_credentials).await?;
+        Ok(QrSignInResult::SignedIn(signed_in_client))
+    }
+
+    async fn wait_for_qr_scan(
+        &mut self,
+        client: &Client,
+        control: &mut QrLoginControl,
+        expires: i32,
+    ) -> Result<QrWaitResult> {
+        let seconds_left = i64::from(expires) - chrono::Utc::now().timestamp();
+        let wait_time = std::cmp::min(
+            Duration::from_secs(seconds_left.max(1) as u64),
+            MAX_QR_TOKEN_WAIT,
+        );
+        let deadline = tokio::time::Instant::now() + wait_time;
+        loop {
+            self.terminal.draw(|frame| Self::render(control, frame))?;
+            if control.is_cancelled() {
+                return Ok(QrWaitResult::Cancelled);
+            }
+            if control.is_phone_requested() {
+                return Ok(QrWaitResult::PhoneRequested);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    return Ok(QrWaitResult::TokenChanged);
+                },
+                maybe_update = client.next_update() => {
+                    if let Update::Raw(tl_types::enums::Update::LoginToken) = maybe_update? {
+                        return Ok(QrWaitResult::TokenChanged);
+                    }
+                },
+                maybe_event = self.event_stream.next() => {
+                    let Some(read_result) = maybe_event else {
+                        return Ok(QrWaitResult::Cancelled);
+                    };
+                    if let Event::Key(kbd_event) = read_result? {
+                        control.handle_keyboard(kbd_event)?;
+                    }
                 }
-                Ok(_) => (),
-                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    // Returns false if user cancelled sign in.
+    async fn phone_sign_in(&mut self, client: &Client) -> Result<bool> {
+        let mut phone_control = SignInControl::new(
+            &self.title,
+            "Enter your phone number (international format):",
+            false,
+        );
+        let login_token = loop {
+            let Some(phone) = self.ask(&mut phone_control).await? else {
+                return Ok(false);
             };
-            log::info!("Signed in!");
+            match client.request_login_code(&phone).await {
+                Ok(token) => break token,
+                Err(e) => phone_control.retry(format!("Failed to request code: {}", e)),
+            }
+        };
+
+        let mut code_control =
+            SignInControl::new(&self.title, "Enter the code you received:", false);
+        let password_token = loop {
+            let Some(code) = self.ask(&mut code_control).await? else {
+                return Ok(false);
+            };
+            match client.sign_in(&login_token, &code).await {
+                Ok(_) => return Ok(true),
+                Err(SignInError::PasswordRequired(password_token)) => break password_token,
+                Err(SignInError::InvalidCode) => {
+                    code_control.retry("Invalid code, try again.".to_string());
+                }
+                Err(SignInError::SignUpRequired { .. }) => {
+                    return Err(eyre!(
+                        "No account with this phone number, sign up with official client first"
+                    ));
+                }
+                Err(e) => code_control.retry(e.to_string()),
+            }
+        };
+        self.check_password(client, password_token).await
+    }
+
+    // Returns false if user cancelled sign in.
+    async fn check_password(
+        &mut self,
+        client: &Client,
+        mut password_token: PasswordToken,
+    ) -> Result<bool> {
+        let mut password_control = SignInControl::new(&self.title, "Enter the 2FA password:", true);
+        password_control.set_hint(password_token.hint().map(str::to_string));
+        loop {
+            let Some(password) = self.ask(&mut password_control).await? else {
+                return Ok(false);
+            };
+            match client
+                .check_password(password_token, password.as_bytes())
+                .await
+            {
+                Ok(_) => return Ok(true),
+                Err(SignInError::InvalidPassword) => {
+                    password_control.retry("Invalid password, try again.".to_string());
+                }
+                Err(e) => password_control.retry(e.to_string()),
+            }
+            // Token is valid for one check only, as its SRP parameters
+            // change after each attempt.
+            password_token = Self::fetch_password_token(client).await?;
+        }
+    }
+
+    async fn fetch_password_token(client: &Client) -> Result<PasswordToken> {
+        let password: tl_types::types::account::Password = client
+            .invoke(&tl_types::functions::account::GetPassword {})
+            .await?
+            .into();
+        Ok(PasswordToken::new(password))
+    }
+
+    // Shows control until user submits a value. Returns None if user
+    // cancelled input.
+    async fn ask(&mut self, control: &mut SignInControl) -> Result<Option<String>> {
+        loop {
+            self.terminal.draw(|frame| Self::render(control, frame))?;
+            if control.is_cancelled() {
+                return Ok(None);
+            }
+            if control.is_submitted() {
+                return Ok(Some(control.value().to_string()));
+            }
+            let Some(read_result) = self.event_stream.next().await else {
+                return Ok(None);
+            };
+            if let Event::Key(kbd_event) = read_result? {
+                control.handle_keyboard(kbd_event)?;
+            }
+        }
+    }
+
+    fn render(control: &mut dyn Control, frame: &mut Frame) {
+        if let Err(e) = control.render(frame, frame.area()) {
+            log::error!("Failed render sign in; Error {:?}", e);
         }
-        storage.save_session(client.session())?;
-        Ok(client)
     }
 }
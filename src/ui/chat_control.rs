@@ -0,0 +1,194 @@
+use super::compose_control::ComposeControl;
+use super::control::Control;
+use super::dialog_picker_control::DialogPickerControl;
+use super::image;
+use super::image_viewer_control::ImageViewerControl;
+use super::messages_list_control::{MessageRequest, MessagesListControl};
+use crate::config::{ImageProtocol, UiConfig};
+use crate::runtime::Runtime;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders};
+use ratatui::Frame;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const PICKER_SIZE_PERCENT: u16 = 80;
+
+#[derive(Clone, Copy)]
+enum Action {
+    StartComposing,
+    AttachFile,
+}
+
+fn default_keymap() -> HashMap<KeyEvent, Action> {
+    HashMap::<KeyEvent, Action>::from([
+        (KeyCode::Char('i').into(), Action::StartComposing),
+        (KeyCode::Char('a').into(), Action::AttachFile),
+    ])
+}
+
+// Messages of the active chat with the input area below them.
+pub struct ChatControl {
+    app_runtime: Arc<Runtime>,
+    messages_list: MessagesListControl,
+    compose: ComposeControl,
+    // Shown while user chooses dialog to forward message with the ID to.
+    forward_picker: Option<(i32, DialogPickerControl)>,
+    // Shown over the whole screen, while user views an image.
+    image_viewer: Option<ImageViewerControl>,
+    image_protocol: ImageProtocol,
+    // Area of the messages list, drawn last time.
+    last_messages_area: Rect,
+    keymap: HashMap<KeyEvent, Action>,
+}
+
+impl ChatControl {
+    pub fn new(app_runtime: Arc<Runtime>, ui_config: &UiConfig) -> Self {
+        Self {
+            messages_list: MessagesListControl::new(app_runtime.clone(), ui_config),
+            compose: ComposeControl::new(app_runtime.clone()),
+            app_runtime,
+            forward_picker: None,
+            image_viewer: None,
+            image_protocol: image::resolve_protocol(ui_config.image_protocol),
+            last_messages_area: Rect::default(),
+            keymap: default_keymap(),
+        }
+    }
+
+    fn handle_action(&mut self, action: Action) -> Result<()> {
+        match action {
+            Action::StartComposing => {
+                self.compose.start_editing();
+            }
+            Action::AttachFile => {
+                self.compose.start_attach();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message_request(&mut self) -> Result<()> {
+        match self.messages_list.take_request() {
+            Some(MessageRequest::Reply(message)) => {
+                self.compose.start_reply(message.id(), message.text());
+            }
+            Some(MessageRequest::Edit(message)) => {
+                self.compose
+                    .start_message_edit(message.id(), message.text());
+            }
+            Some(MessageRequest::Forward(message)) => {
+                let picker = DialogPickerControl::new(self.app_runtime.clone(), "Forward to")?;
+                self.forward_picker = Some((message.id(), picker));
+            }
+            Some(MessageRequest::ViewImage(path)) => {
+                self.image_viewer = Some(ImageViewerControl::new(&path, self.image_protocol)?);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn handle_picker_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        let Some((message_id, picker)) = self.forward_picker.as_mut() else {
+            return Ok(());
+        };
+        picker.handle_keyboard(event)?;
+        if picker.is_closed() {
+            if let Some(chat) = picker.chosen() {
+                self.app_runtime.forward_message(*message_id, chat)?;
+            }
+            self.forward_picker = None;
+        }
+        Ok(())
+    }
+
+    fn handle_viewer_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        let Some(viewer) = self.image_viewer.as_mut() else {
+            return Ok(());
+        };
+        viewer.handle_keyboard(event)?;
+        if viewer.is_closed() {
+            self.image_viewer = None;
+            image::clear_images(self.image_protocol);
+        }
+        Ok(())
+    }
+}
+
+impl Control for ChatControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if self.image_viewer.is_some() {
+            self.handle_viewer_keyboard(event)
+        } else if self.forward_picker.is_some() {
+            self.handle_picker_keyboard(event)
+        } else if self.compose.captures_keyboard() {
+            self.compose.handle_keyboard(event)
+        } else if self.messages_list.captures_keyboard() {
+            self.messages_list.handle_keyboard(event)
+        } else if let Some(action) = self.keymap.get(&event) {
+            self.handle_action(*action)
+        } else {
+            self.messages_list.handle_keyboard(event)?;
+            self.handle_message_request()
+        }
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        // One more line for the separator.
+        let compose_height = self.compose.desired_height(rect.width) + 1;
+        let [messages_area, compose_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(compose_height)]).areas(rect);
+        let separator_color = if self.compose.captures_keyboard() {
+            Color::Yellow
+        } else {
+            Color::White
+        };
+        let separator = Block::new()
+            .borders(Borders::TOP)
+            .title(self.compose.title())
+            .style(Style::default().fg(separator_color));
+        let compose_inner_area = separator.inner(compose_area);
+        frame.render_widget(separator, compose_area);
+
+        self.messages_list.render(frame, messages_area)?;
+        self.last_messages_area = messages_area;
+        self.compose.render(frame, compose_inner_area)?;
+        if let Some((_, picker)) = self.forward_picker.as_mut() {
+            let [picker_area] = Layout::horizontal([Constraint::Percentage(PICKER_SIZE_PERCENT)])
+                .flex(Flex::Center)
+                .areas(rect);
+            let [picker_area] = Layout::vertical([Constraint::Percentage(PICKER_SIZE_PERCENT)])
+                .flex(Flex::Center)
+                .areas(picker_area);
+            picker.render(frame, picker_area)?;
+        }
+        if let Some(viewer) = self.image_viewer.as_mut() {
+            viewer.render(frame, frame.area())?;
+        }
+        Ok(())
+    }
+
+    fn render_images(&mut self, frame: &mut Frame, _rect: Rect) -> Result<()> {
+        if self.image_viewer.is_some() || self.forward_picker.is_some() {
+            self.messages_list.hide_images();
+            return Ok(());
+        }
+        self.messages_list
+            .render_images(frame, self.last_messages_area)
+    }
+
+    fn hide_images(&mut self) {
+        self.messages_list.hide_images();
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        self.image_viewer.is_some()
+            || self.forward_picker.is_some()
+            || self.compose.captures_keyboard()
+            || self.messages_list.captures_keyboard()
+    }
+}
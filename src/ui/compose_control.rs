@@ -0,0 +1,416 @@
+use super::control::Control;
+use crate::runtime::{FileKind, Runtime};
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::layout::{Position, Rect};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::Paragraph;
+use ratatui::Frame;
+use std::cmp;
+use std::path::PathBuf;
+use std::sync::Arc;
+use unicode_width::UnicodeWidthChar;
+
+// Input area grows with text up to this number of lines, then scrolls.
+const MAX_VISIBLE_LINES: u16 = 8;
+
+enum Mode {
+    NewMessage,
+    // Contains ID of the chat, ID and first line of the message replied to.
+    Reply(i64, i32, String),
+    // Contains ID of the chat and ID of the edited message.
+    Edit(i64, i32),
+    // Text is path of the file to send.
+    AttachPath,
+    // Text is caption of the file to send.
+    AttachCaption(PathBuf, FileKind),
+}
+
+// Multi-line text input. Enter sends the message, Alt+Enter or Shift+Enter
+// starts a new line, Esc stops editing and cancels reply or edit.
+// When a file is attached, input takes its path first, with Tab completing
+// it, then the caption, with Tab changing how the file is sent.
+pub struct ComposeControl {
+    app_runtime: Arc<Runtime>,
+    text: Vec<char>,
+    // Index in `text` before which new characters are inserted.
+    cursor: usize,
+    editing: bool,
+    mode: Mode,
+    // Message text, kept while file path and caption, or text of the
+    // edited message are typed.
+    stashed_text: Vec<char>,
+    // Shown in the title while file path is typed, e.g. path completions.
+    path_hint: Option<String>,
+}
+
+struct TextLayout {
+    lines: Vec<String>,
+    cursor_x: u16,
+    cursor_y: u16,
+}
+
+impl ComposeControl {
+    pub fn new(app_runtime: Arc<Runtime>) -> Self {
+        Self {
+            app_runtime,
+            text: Vec::new(),
+            cursor: 0,
+            editing: false,
+            mode: Mode::NewMessage,
+            stashed_text: Vec::new(),
+            path_hint: None,
+        }
+    }
+
+    pub fn start_editing(&mut self) {
+        self.editing = true;
+    }
+
+    /// Starts composing reply to the message, keeping typed text.
+    pub fn start_reply(&mut self, message_id: i32, message_text: &str) {
+        let Some(chat_id) = self.app_runtime.get_active_chat_id() else {
+            return;
+        };
+        self.cancel_mode();
+        let first_line = message_text.lines().next().unwrap_or_default().to_string();
+        self.mode = Mode::Reply(chat_id, message_id, first_line);
+        self.editing = true;
+    }
+
+    /// Starts editing text of the message, sent by user, keeping typed
+    /// text until the edit is sent or cancelled.
+    pub fn start_message_edit(&mut self, message_id: i32, message_text: &str) {
+        let Some(chat_id) = self.app_runtime.get_active_chat_id() else {
+            return;
+        };
+        self.cancel_mode();
+        self.stashed_text = std::mem::take(&mut self.text);
+        self.mode = Mode::Edit(chat_id, message_id);
+        self.text = message_text.chars().collect();
+        self.cursor = self.text.len();
+        self.editing = true;
+    }
+
+    /// Starts choosing file to send, keeping typed text.
+    pub fn start_attach(&mut self) {
+        self.cancel_mode();
+        self.stashed_text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.path_hint = None;
+        self.mode = Mode::AttachPath;
+        self.editing = true;
+    }
+
+    /// Title describing what is composed.
+    pub fn title(&self) -> String {
+        match &self.mode {
+            Mode::NewMessage => "Message".to_string(),
+            Mode::Reply(_, message_id, first_line) => {
+                format!("Reply to #{}: {}", message_id, first_line)
+            }
+            Mode::Edit(_, message_id) => format!("Edit message #{}", message_id),
+            Mode::AttachPath => match &self.path_hint {
+                Some(hint) => format!("File to send: {}", hint),
+                None => "File to send (Tab completes path)".to_string(),
+            },
+            Mode::AttachCaption(path, kind) => format!(
+                "Caption of {} {} (Tab changes type)",
+                kind.name(),
+                path.display()
+            ),
+        }
+    }
+
+    // Returns to composing a new message, restoring its draft.
+    fn cancel_mode(&mut self) {
+        match self.mode {
+            // Draft was stashed, edited text or file path is not a part of it.
+            Mode::Edit(..) | Mode::AttachPath | Mode::AttachCaption(..) => {
+                self.text = std::mem::take(&mut self.stashed_text);
+                self.cursor = self.text.len();
+            }
+            Mode::NewMessage | Mode::Reply(..) => {}
+        }
+        self.mode = Mode::NewMessage;
+    }
+
+    // Reply and edit refer to messages of the chat, that was active when
+    // they started, so they are cancelled when another chat is opened.
+    fn cancel_mode_of_inactive_chat(&mut self) {
+        let mode_chat_id = match self.mode {
+            Mode::Reply(chat_id, ..) | Mode::Edit(chat_id, _) => chat_id,
+            _ => return,
+        };
+        if self.app_runtime.get_active_chat_id() != Some(mode_chat_id) {
+            self.cancel_mode();
+        }
+    }
+
+    fn handle_tab(&mut self) {
+        match &mut self.mode {
+            Mode::AttachPath => self.complete_path(),
+            Mode::AttachCaption(_, kind) => *kind = kind.next(),
+            _ => {}
+        }
+    }
+
+    // Completes the last component of the typed path to the longest common
+    // prefix of matching directory entries.
+    fn complete_path(&mut self) {
+        let text: String = self.text.iter().collect();
+        let (dir, prefix) = match text.rfind('/') {
+            Some(pos) => text.split_at(pos + 1),
+            None => ("", text.as_str()),
+        };
+        let dir_path = if dir.is_empty() {
+            PathBuf::from(".")
+        } else {
+            Self::expand_path(dir)
+        };
+        let Ok(entries) = std::fs::read_dir(&dir_path) else {
+            self.path_hint = Some("no such directory".to_string());
+            return;
+        };
+        let mut matches: Vec<(String, bool)> = entries
+            .flatten()
+            .map(|entry| {
+                let is_dir = entry.path().is_dir();
+                (entry.file_name().to_string_lossy().to_string(), is_dir)
+            })
+            // Hidden files are completed only when asked for explicitly.
+            .filter(|(name, _)| {
+                name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.'))
+            })
+            .collect();
+        matches.sort();
+        let completed = match matches.as_slice() {
+            [] => {
+                self.path_hint = Some("no matches".to_string());
+                return;
+            }
+            [(name, is_dir)] => {
+                self.path_hint = None;
+                if *is_dir {
+                    format!("{}/", name)
+                } else {
+                    name.clone()
+                }
+            }
+            [(first, _), ..] => {
+                let names: Vec<&str> = matches.iter().map(|(name, _)| name.as_str()).collect();
+                self.path_hint = Some(names.join(" "));
+                let mut common = first.clone();
+                for (name, _) in matches.iter() {
+                    while !name.starts_with(common.as_str()) {
+                        common.pop();
+                    }
+                }
+                common
+            }
+        };
+        self.text = format!("{}{}", dir, completed).chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    // Expands "~" to the home directory.
+    fn expand_path(path: &str) -> PathBuf {
+        match (path.strip_prefix('~'), std::env::var_os("HOME")) {
+            (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+                PathBuf::from(home).join(rest.trim_start_matches('/'))
+            }
+            _ => PathBuf::from(path),
+        }
+    }
+
+    fn choose_path(&mut self) {
+        let text: String = self.text.iter().collect();
+        let path = Self::expand_path(text.trim());
+        if !path.is_file() {
+            self.path_hint = Some(format!("{} is not a file", path.display()));
+            return;
+        }
+        let kind = FileKind::for_path(&path);
+        self.mode = Mode::AttachCaption(path, kind);
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Number of lines the control wants to occupy for given width.
+    pub fn desired_height(&self, width: u16) -> u16 {
+        let line_count = self.layout(width).lines.len();
+        cmp::min(MAX_VISIBLE_LINES, line_count as u16)
+    }
+
+    fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn current_line_start(&self) -> usize {
+        self.text[..self.cursor]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |pos| pos + 1)
+    }
+
+    fn current_line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(self.text.len(), |pos| self.cursor + pos)
+    }
+
+    fn send(&mut self) -> Result<()> {
+        self.cancel_mode_of_inactive_chat();
+        let text: String = self.text.iter().collect();
+        match &self.mode {
+            Mode::AttachPath => {
+                self.choose_path();
+                return Ok(());
+            }
+            Mode::AttachCaption(path, kind) => {
+                // Caption may be empty.
+                self.app_runtime.send_file(path.clone(), *kind, text)?;
+                self.cancel_mode();
+                return Ok(());
+            }
+            _ => {}
+        }
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        match self.mode {
+            Mode::NewMessage => self.app_runtime.send_message(text, None)?,
+            Mode::Reply(_, message_id, _) => {
+                self.app_runtime.send_message(text, Some(message_id))?
+            }
+            Mode::Edit(_, message_id) => {
+                self.app_runtime.edit_message(message_id, text)?;
+                self.editing = false;
+                self.cancel_mode();
+                return Ok(());
+            }
+            Mode::AttachPath | Mode::AttachCaption(..) => {}
+        }
+        self.text.clear();
+        self.cursor = 0;
+        self.mode = Mode::NewMessage;
+        Ok(())
+    }
+
+    fn layout(&self, width: u16) -> TextLayout {
+        let width = cmp::max(1, width);
+        let mut lines = vec![String::new()];
+        let mut x = 0;
+        let mut cursor_x = 0;
+        let mut cursor_y = 0;
+        for (index, c) in self.text.iter().enumerate() {
+            let char_width = c.width().unwrap_or(0) as u16;
+            if *c != '\n' && x + char_width > width {
+                lines.push(String::new());
+                x = 0;
+            }
+            if index == self.cursor {
+                cursor_x = x;
+                cursor_y = lines.len() - 1;
+            }
+            if *c == '\n' {
+                lines.push(String::new());
+                x = 0;
+            } else {
+                lines.last_mut().unwrap().push(*c);
+                x += char_width;
+            }
+        }
+        if self.cursor == self.text.len() {
+            if x >= width {
+                lines.push(String::new());
+                x = 0;
+            }
+            cursor_x = x;
+            cursor_y = lines.len() - 1;
+        }
+        TextLayout {
+            lines,
+            cursor_x,
+            cursor_y: cursor_y as u16,
+        }
+    }
+}
+
+impl Control for ComposeControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        match event.code {
+            KeyCode::Esc => {
+                self.editing = false;
+                self.cancel_mode();
+            }
+            KeyCode::Enter
+                if event
+                    .modifiers
+                    .intersects(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+            {
+                self.insert('\n');
+            }
+            KeyCode::Enter => {
+                self.send()?;
+            }
+            KeyCode::Tab => {
+                self.handle_tab();
+            }
+            KeyCode::Char(c)
+                if !event
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                self.insert(c);
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            }
+            KeyCode::Left => {
+                self.cursor = self.cursor.saturating_sub(1);
+            }
+            KeyCode::Right => {
+                self.cursor = cmp::min(self.text.len(), self.cursor + 1);
+            }
+            KeyCode::Home => {
+                self.cursor = self.current_line_start();
+            }
+            KeyCode::End => {
+                self.cursor = self.current_line_end();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        self.cancel_mode_of_inactive_chat();
+        if rect.is_empty() {
+            return Ok(());
+        }
+        let layout = self.layout(rect.width);
+        // Scroll so the line with cursor is always visible.
+        let scroll = layout.cursor_y.saturating_sub(rect.height - 1);
+        let lines: Vec<Line> = layout.lines.into_iter().map(Line::from).collect();
+        let paragraph = Paragraph::new(Text::from(lines)).scroll((scroll, 0));
+        frame.render_widget(paragraph, rect);
+        if self.editing {
+            frame.set_cursor_position(Position::new(
+                rect.x + layout.cursor_x,
+                rect.y + layout.cursor_y - scroll,
+            ));
+        }
+        Ok(())
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        self.editing
+    }
+}
@@ -6,4 +6,16 @@ use ratatui::Frame;
 pub trait Control {
     fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()>;
     fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()>;
+    // Draws images of the control into `rect`, after all text of the frame
+    // is rendered. Terminals draw kitty images over any text, so parents
+    // call `hide_images` instead, while popups cover the control.
+    fn render_images(&mut self, _frame: &mut Frame, _rect: Rect) -> Result<()> {
+        Ok(())
+    }
+    fn hide_images(&mut self) {}
+    // Text input controls return true here, so parents pass all keys to
+    // them instead of handling shortcuts.
+    fn captures_keyboard(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,141 @@
+use super::control::Control;
+use crate::runtime::Runtime;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use grammers_client::types::Chat;
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use std::sync::Arc;
+use unicode_width::UnicodeWidthStr;
+
+// Popup with filter input and list of dialogs to choose one of them.
+// Enter chooses the selected dialog, Esc closes the popup without choice.
+pub struct DialogPickerControl {
+    title: String,
+    chats: Vec<Chat>,
+    filter: String,
+    // Chats, that match the filter.
+    shown_chats: Vec<Chat>,
+    list_state: ListState,
+    chosen: Option<Chat>,
+    closed: bool,
+}
+
+impl DialogPickerControl {
+    pub fn new(app_runtime: Arc<Runtime>, title: &str) -> Result<Self> {
+        let mut chats: Vec<Chat> = app_runtime
+            .get_dialogs()?
+            .iter()
+            .map(|dialog| dialog.chat().clone())
+            .collect();
+        chats.sort_by(|first, second| first.name().cmp(second.name()));
+        let mut result = Self {
+            title: title.to_string(),
+            chats,
+            filter: String::new(),
+            shown_chats: Vec::new(),
+            list_state: ListState::default(),
+            chosen: None,
+            closed: false,
+        };
+        result.on_filter_changed();
+        Ok(result)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns dialog chosen by user, if any.
+    pub fn chosen(&self) -> Option<Chat> {
+        self.chosen.clone()
+    }
+
+    fn on_filter_changed(&mut self) {
+        let filter = self.filter.to_lowercase();
+        self.shown_chats = self
+            .chats
+            .iter()
+            .filter(|chat| chat.name().to_lowercase().contains(&filter))
+            .cloned()
+            .collect();
+        if self.shown_chats.is_empty() {
+            self.list_state.select(None);
+        } else {
+            self.list_state.select_first();
+        }
+    }
+
+    fn choose_selected(&mut self) {
+        if let Some(selected) = self.list_state.selected() {
+            self.chosen = self.shown_chats.get(selected).cloned();
+            self.closed = self.chosen.is_some();
+        }
+    }
+}
+
+impl Control for DialogPickerControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        let with_control = event.modifiers.contains(KeyModifiers::CONTROL);
+        match event.code {
+            KeyCode::Esc => {
+                self.closed = true;
+            }
+            KeyCode::Enter => self.choose_selected(),
+            KeyCode::Down => self.list_state.select_next(),
+            KeyCode::Char('n') if with_control => self.list_state.select_next(),
+            KeyCode::Up => self.list_state.select_previous(),
+            KeyCode::Char('p') if with_control => self.list_state.select_previous(),
+            KeyCode::Backspace => {
+                self.filter.pop();
+                self.on_filter_changed();
+            }
+            KeyCode::Char(c) if !with_control => {
+                self.filter.push(c);
+                self.on_filter_changed();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        frame.render_widget(Clear, rect);
+        let border = Block::bordered()
+            .title(self.title.as_str())
+            .style(Style::default().fg(Color::Yellow));
+        let inner_area = border.inner(rect);
+        frame.render_widget(border, rect);
+        let [filter_area, list_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner_area);
+
+        let filter_line = format!("> {}", self.filter);
+        let cursor_x = filter_area.x + filter_line.width() as u16;
+        frame.render_widget(
+            Paragraph::new(filter_line).style(Style::new().white()),
+            filter_area,
+        );
+        frame.set_cursor_position(Position::new(
+            std::cmp::min(cursor_x, filter_area.right().saturating_sub(1)),
+            filter_area.y,
+        ));
+
+        let items: Vec<_> = self
+            .shown_chats
+            .iter()
+            .map(|chat| ListItem::new(chat.name().to_owned()))
+            .collect();
+        let list = List::new(items)
+            .style(Style::new().white())
+            .highlight_style(Style::new().on_dark_gray());
+        frame.render_stateful_widget(list, list_area, &mut self.list_state);
+        Ok(())
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        true
+    }
+}
@@ -20,9 +20,12 @@ enum Action {
     SortByName,
     SortByType,
     SortByUnreadCount,
+    SortByRecency,
 }
 
 enum SortOrder {
+    // Pinned dialogs, then dialogs with the newest messages first.
+    Recency,
     Name,
     Type,
     UnreadCount,
@@ -44,6 +47,10 @@ fn default_keymap() -> HashMap<KeyEvent, Action> {
             KeyEvent::new(KeyCode::Char('u'), KeyModifiers::CONTROL),
             Action::SortByUnreadCount,
         ),
+        (
+            KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
+            Action::SortByRecency,
+        ),
         (KeyCode::Enter.into(), Action::Activate),
     ])
 }
@@ -65,6 +72,9 @@ pub struct DialogsListControl {
     app_runtime: Arc<Runtime>,
     sort_order: SortOrder,
     list_state: ListState,
+    // Chat of the selected item, so selection follows the chat, when
+    // dialogs are sorted again.
+    selected_chat_id: Option<i64>,
     last_drawn_items: Vec<ListItem>,
 }
 
@@ -74,7 +84,8 @@ impl DialogsListControl {
             keymap: default_keymap(),
             list_state: ListState::default(),
             app_runtime,
-            sort_order: SortOrder::Name,
+            sort_order: SortOrder::Recency,
+            selected_chat_id: None,
             last_drawn_items: Vec::new(),
         }
     }
@@ -83,9 +94,11 @@ impl DialogsListControl {
         match action {
             Action::SelectNext => {
                 self.list_state.select_next();
+                self.remember_selected_chat();
             }
             Action::SelectPrev => {
                 self.list_state.select_previous();
+                self.remember_selected_chat();
             }
             Action::SortByName => {
                 self.sort_order = SortOrder::Name;
@@ -96,16 +109,28 @@ impl DialogsListControl {
             Action::SortByUnreadCount => {
                 self.sort_order = SortOrder::UnreadCount;
             }
+            Action::SortByRecency => {
+                self.sort_order = SortOrder::Recency;
+            }
             Action::Activate => {
-                if let Some(selected) = self.list_state.selected() {
-                    let chat_id = self.last_drawn_items[selected].chat_id;
-                    self.app_runtime.set_active_dialog(chat_id);
+                if let Some(chat_id) = self.selected_chat_id {
+                    self.app_runtime.set_active_dialog(chat_id)?;
                 }
             }
         }
         Ok(())
     }
 
+    fn remember_selected_chat(&mut self) {
+        let Some(last_index) = self.last_drawn_items.len().checked_sub(1) else {
+            return;
+        };
+        self.selected_chat_id = self
+            .list_state
+            .selected()
+            .map(|index| self.last_drawn_items[std::cmp::min(index, last_index)].chat_id);
+    }
+
     fn get_raw_dialog(dialog: &Dialog) -> &tl_types::types::Dialog {
         match &dialog.raw {
             tl_types::enums::Dialog::Dialog(d) => d,
@@ -134,6 +159,20 @@ impl DialogsListControl {
 
     fn make_list_items(&self, mut dialogs: Vec<Dialog>) -> Vec<ListItem> {
         match self.sort_order {
+            SortOrder::Recency => {
+                // Dialogs without known messages go last.
+                dialogs.sort_by_cached_key(|dialog| {
+                    let date = self
+                        .app_runtime
+                        .get_last_message_date(dialog)
+                        .unwrap_or_else(|e| {
+                            log::error!("Failed get last message date; Error {:?}", e);
+                            None
+                        });
+                    let pinned = Self::get_raw_dialog(dialog).pinned;
+                    std::cmp::Reverse((pinned, date))
+                });
+            }
             SortOrder::Name => {
                 dialogs.sort_by(|first, second| first.chat().name().cmp(second.chat().name()));
             }
@@ -197,12 +236,20 @@ impl Control for DialogsListControl {
     fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
         let dialogs = self.app_runtime.get_dialogs()?;
         self.last_drawn_items = self.make_list_items(dialogs);
+        if let Some(index) = self
+            .last_drawn_items
+            .iter()
+            .position(|item| Some(item.chat_id) == self.selected_chat_id)
+        {
+            self.list_state.select(Some(index));
+        }
         let list = List::new(self.last_drawn_items.clone())
             .style(Style::new().white())
             .highlight_style(Style::new().yellow())
             .repeat_highlight_symbol(true)
             .direction(ListDirection::TopToBottom);
         frame.render_stateful_widget(list, rect, &mut self.list_state);
+        self.remember_selected_chat();
         Ok(())
     }
 }
@@ -0,0 +1,381 @@
+use crate::config::ImageProtocol;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use color_eyre::Result;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::prelude::*;
+use ratatui::widgets::{Paragraph, Widget};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Used when the terminal doesn't report its size in pixels.
+const DEFAULT_CELL_SIZE: (u32, u32) = (10, 20);
+// Kitty limits size of one escape sequence payload.
+const KITTY_CHUNK_SIZE: usize = 4096;
+// Each sixel character encodes that many pixel rows.
+const SIXEL_BAND_HEIGHT: u32 = 6;
+// Pixels more transparent than that are not drawn.
+const MIN_OPAQUE_ALPHA: u8 = 128;
+
+// Kitty images are replaced and deleted by their IDs, so several images
+// may be shown at once.
+static NEXT_KITTY_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Returns protocol to draw images with. `Auto` is resolved by the
+/// terminal environment, as querying the terminal would race with reading
+/// keyboard events.
+pub fn resolve_protocol(configured: ImageProtocol) -> ImageProtocol {
+    if configured != ImageProtocol::Auto {
+        return configured;
+    }
+    let term = std::env::var("TERM").unwrap_or_default();
+    let term_program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+    if std::env::var_os("KITTY_WINDOW_ID").is_some()
+        || term == "xterm-kitty"
+        || term == "xterm-ghostty"
+        || term_program == "WezTerm"
+        || term_program == "ghostty"
+    {
+        ImageProtocol::Kitty
+    } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+        ImageProtocol::Sixel
+    } else {
+        ImageProtocol::HalfBlocks
+    }
+}
+
+/// Removes images, drawn by the graphics protocol, from the screen. Kitty
+/// keeps them over the text until deleted explicitly, while sixel images
+/// are overwritten by the text.
+pub fn clear_images(protocol: ImageProtocol) {
+    if protocol != ImageProtocol::Kitty {
+        return;
+    }
+    write_sequence("\x1b_Ga=d,d=A,q=2\x1b\\");
+}
+
+fn write_sequence(sequence: &str) {
+    let mut stdout = std::io::stdout();
+    if let Err(e) = stdout
+        .write_all(sequence.as_bytes())
+        .and_then(|_| stdout.flush())
+    {
+        log::error!("Failed write image sequence; Error {:?}", e);
+    }
+}
+
+/// Returns lines, that draw the image with half-block characters, two
+/// pixels per cell, fitted into `max_width` x `max_height` cells.
+pub fn half_block_lines(
+    image: &DynamicImage,
+    max_width: u16,
+    max_height: u16,
+) -> Vec<Line<'static>> {
+    let resized = image
+        .resize(
+            u32::from(max_width),
+            u32::from(max_height) * 2,
+            FilterType::Triangle,
+        )
+        .to_rgba8();
+    let (width, height) = resized.dimensions();
+    (0..height)
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..width)
+                .map(|x| {
+                    let top = opaque_color(resized.get_pixel(x, y));
+                    let bottom = if y + 1 < height {
+                        opaque_color(resized.get_pixel(x, y + 1))
+                    } else {
+                        None
+                    };
+                    match (top, bottom) {
+                        (Some(top), Some(bottom)) => {
+                            Span::styled("▀", Style::new().fg(top).bg(bottom))
+                        }
+                        (Some(top), None) => Span::styled("▀", Style::new().fg(top)),
+                        (None, Some(bottom)) => Span::styled("▄", Style::new().fg(bottom)),
+                        (None, None) => Span::raw(" "),
+                    }
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect()
+}
+
+fn opaque_color(pixel: &Rgba<u8>) -> Option<Color> {
+    let [r, g, b, a] = pixel.0;
+    (a >= MIN_OPAQUE_ALPHA).then_some(Color::Rgb(r, g, b))
+}
+
+/// Decoded image, drawn fitted into a rect with the given protocol.
+pub struct TerminalImage {
+    image: DynamicImage,
+    protocol: ImageProtocol,
+    kitty_id: u32,
+    // Escape sequence of the graphics protocol and the area it was made
+    // for, as encoding is slow.
+    encoded: Option<(Rect, String)>,
+    // Half-block lines and size of the rect they were made for, as
+    // resizing is slow too.
+    half_blocks: Option<((u16, u16), Vec<Line<'static>>)>,
+}
+
+impl TerminalImage {
+    pub fn open(path: &Path, protocol: ImageProtocol) -> Result<Self> {
+        Ok(Self::new(image::open(path)?, protocol))
+    }
+
+    /// Opens image to preview in at most `max_width` x `max_height` cells.
+    /// Image is scaled down to that size and prepared for drawing, so it
+    /// is slow, but the preview is rendered fast.
+    pub fn open_preview(
+        path: &Path,
+        protocol: ImageProtocol,
+        max_width: u16,
+        max_height: u16,
+    ) -> Result<Self> {
+        let image = image::open(path)?;
+        let (cell_width, cell_height) = cell_size();
+        let (box_width, box_height) = (
+            u32::from(max_width) * cell_width,
+            u32::from(max_height) * cell_height,
+        );
+        let image = if image.width() > box_width || image.height() > box_height {
+            image.thumbnail(box_width, box_height)
+        } else {
+            image
+        };
+        let mut result = Self::new(image, protocol);
+        let area = result.fit(Rect::new(0, 0, max_width, max_height));
+        result.prepare(area)?;
+        Ok(result)
+    }
+
+    fn new(image: DynamicImage, protocol: ImageProtocol) -> Self {
+        Self {
+            image,
+            protocol,
+            kitty_id: NEXT_KITTY_ID.fetch_add(1, Ordering::Relaxed),
+            encoded: None,
+            half_blocks: None,
+        }
+    }
+
+    /// Removes the image from the screen, when it is not rendered anymore.
+    /// Only kitty images need it, sixel ones are overwritten by the text.
+    pub fn hide(&self) {
+        if self.protocol == ImageProtocol::Kitty {
+            write_sequence(&format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", self.kitty_id));
+        }
+    }
+
+    pub fn widget(&mut self) -> ImageWidget<'_> {
+        ImageWidget { image: self }
+    }
+
+    /// Returns area of `rect`, that the image takes, when fitted keeping
+    /// its aspect ratio and centered.
+    pub fn fit(&self, rect: Rect) -> Rect {
+        let (cell_width, cell_height) = cell_size();
+        let (image_width, image_height) = (self.image.width().max(1), self.image.height().max(1));
+        let scale = f64::min(
+            f64::from(u32::from(rect.width) * cell_width) / f64::from(image_width),
+            f64::from(u32::from(rect.height) * cell_height) / f64::from(image_height),
+        );
+        let columns = (f64::from(image_width) * scale / f64::from(cell_width)).round() as u16;
+        let rows = (f64::from(image_height) * scale / f64::from(cell_height)).round() as u16;
+        let [area] = Layout::horizontal([Constraint::Length(columns.clamp(1, rect.width))])
+            .flex(Flex::Center)
+            .areas(rect);
+        let [area] = Layout::vertical([Constraint::Length(rows.clamp(1, rect.height))])
+            .flex(Flex::Center)
+            .areas(area);
+        area
+    }
+
+    // Makes drawing of the image for `rect`, unless it is made already.
+    fn prepare(&mut self, rect: Rect) -> Result<()> {
+        if matches!(
+            self.protocol,
+            ImageProtocol::Auto | ImageProtocol::HalfBlocks
+        ) {
+            let size = (rect.width, rect.height);
+            if !matches!(&self.half_blocks, Some((lines_size, _)) if *lines_size == size) {
+                let lines = half_block_lines(&self.image, rect.width, rect.height);
+                self.half_blocks = Some((size, lines));
+            }
+            return Ok(());
+        }
+        let area = self.fit(rect);
+        // Sequence doesn't depend on the position, it is printed at the
+        // first cell of the area.
+        let is_cached = matches!(&self.encoded, Some((encoded_area, _))
+            if (encoded_area.width, encoded_area.height) == (area.width, area.height));
+        if !is_cached {
+            self.encoded = Some((area, self.encode(area)?));
+        }
+        Ok(())
+    }
+
+    fn encode(&self, area: Rect) -> Result<String> {
+        match self.protocol {
+            ImageProtocol::Kitty => self.encode_kitty(area),
+            ImageProtocol::Sixel => {
+                let (cell_width, cell_height) = cell_size();
+                let resized = self.image.resize_exact(
+                    u32::from(area.width) * cell_width,
+                    u32::from(area.height) * cell_height,
+                    FilterType::Triangle,
+                );
+                Ok(encode_sixel(&resized.to_rgba8()))
+            }
+            ImageProtocol::Auto | ImageProtocol::HalfBlocks => Ok(String::new()),
+        }
+    }
+
+    // Image is sent as PNG, terminal scales it to the area itself.
+    fn encode_kitty(&self, area: Rect) -> Result<String> {
+        let mut png = Vec::new();
+        self.image
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)?;
+        let payload = STANDARD.encode(png);
+        let chunks: Vec<&[u8]> = payload.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+        // Image drawn before is deleted, it may have another size or place.
+        let mut result = format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", self.kitty_id);
+        for (index, chunk) in chunks.iter().enumerate() {
+            let more = u8::from(index + 1 < chunks.len());
+            let chunk = std::str::from_utf8(chunk)?;
+            if index == 0 {
+                // C=1 keeps the cursor, so the text around is not shifted.
+                write!(
+                    result,
+                    "\x1b_Ga=T,f=100,i={},q=2,C=1,c={},r={},m={};{}\x1b\\",
+                    self.kitty_id, area.width, area.height, more, chunk
+                )?;
+            } else {
+                write!(result, "\x1b_Gm={};{}\x1b\\", more, chunk)?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Draws the image fitted into the rect it is rendered to.
+pub struct ImageWidget<'a> {
+    image: &'a mut TerminalImage,
+}
+
+impl Widget for ImageWidget<'_> {
+    fn render(self, rect: Rect, buf: &mut Buffer) {
+        if rect.is_empty() {
+            return;
+        }
+        if let Err(e) = self.image.prepare(rect) {
+            log::error!("Failed encode image; Error {:?}", e);
+            return;
+        }
+        if let Some((_, lines)) = &self.image.half_blocks {
+            let width = lines.first().map_or(0, |line| line.width()) as u16;
+            let [area] = Layout::horizontal([Constraint::Length(width)])
+                .flex(Flex::Center)
+                .areas(rect);
+            let [area] = Layout::vertical([Constraint::Length(lines.len() as u16)])
+                .flex(Flex::Center)
+                .areas(area);
+            Paragraph::new(lines.clone()).render(area, buf);
+            return;
+        }
+        let area = self.image.fit(rect);
+        let Some((_, sequence)) = &self.image.encoded else {
+            return;
+        };
+        // Terminal draws the image over the area, when the sequence is
+        // printed at its first cell, so other cells must be left alone.
+        for y in area.top()..area.bottom() {
+            for x in area.left()..area.right() {
+                buf[(x, y)].set_skip(true);
+            }
+        }
+        let first_cell = &mut buf[(area.x, area.y)];
+        first_cell.set_skip(false);
+        first_cell.set_symbol(sequence);
+    }
+}
+
+// Returns size of the terminal cell in pixels.
+fn cell_size() -> (u32, u32) {
+    match crossterm::terminal::window_size() {
+        Ok(size) if size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0 => (
+            u32::from(size.width / size.columns).max(1),
+            u32::from(size.height / size.rows).max(1),
+        ),
+        _ => DEFAULT_CELL_SIZE,
+    }
+}
+
+// Encodes image with 6x6x6 color cube palette. Transparent pixels are
+// left with the terminal background.
+fn encode_sixel(image: &RgbaImage) -> String {
+    let (width, height) = image.dimensions();
+    let color_index = |x: u32, y: u32| -> Option<usize> {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let level = |c: u8| (usize::from(c) * 5 + 127) / 255;
+        (a >= MIN_OPAQUE_ALPHA).then(|| level(r) * 36 + level(g) * 6 + level(b))
+    };
+    // P2=1 keeps background of transparent pixels.
+    let mut result = format!("\x1bP0;1;0q\"1;1;{};{}", width, height);
+    for index in 0..216 {
+        let (r, g, b) = (index / 36, index / 6 % 6, index % 6);
+        let _ = write!(result, "#{};2;{};{};{}", index, r * 20, g * 20, b * 20);
+    }
+    for band_top in (0..height).step_by(SIXEL_BAND_HEIGHT as usize) {
+        let band_height = std::cmp::min(SIXEL_BAND_HEIGHT, height - band_top);
+        let band: Vec<Vec<Option<usize>>> = (0..band_height)
+            .map(|dy| (0..width).map(|x| color_index(x, band_top + dy)).collect())
+            .collect();
+        let colors: BTreeSet<usize> = band.iter().flatten().flatten().copied().collect();
+        for color in colors {
+            let _ = write!(result, "#{}", color);
+            let sixels: Vec<char> = (0..width as usize)
+                .map(|x| {
+                    let bits = (0..band_height as usize)
+                        .filter(|dy| band[*dy][x] == Some(color))
+                        .fold(0u8, |bits, dy| bits | (1 << dy));
+                    char::from(63 + bits)
+                })
+                .collect();
+            push_run_length_encoded(&mut result, &sixels);
+            // Next color is drawn over the same band.
+            result.push('$');
+        }
+        result.push('-');
+    }
+    result.push_str("\x1b\\");
+    result
+}
+
+fn push_run_length_encoded(result: &mut String, sixels: &[char]) {
+    let mut start = 0;
+    while start < sixels.len() {
+        let c = sixels[start];
+        let run = sixels[start..]
+            .iter()
+            .take_while(|other| **other == c)
+            .count();
+        if run > 3 {
+            let _ = write!(result, "!{}{}", run, c);
+        } else {
+            result.extend(std::iter::repeat_n(c, run));
+        }
+        start += run;
+    }
+}
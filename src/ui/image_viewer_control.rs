@@ -0,0 +1,59 @@
+use super::control::Control;
+use super::image::TerminalImage;
+use crate::config::ImageProtocol;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::layout::Rect;
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear};
+use ratatui::Frame;
+use std::path::Path;
+
+// Shows image over the whole screen. Esc or q closes the viewer.
+pub struct ImageViewerControl {
+    title: String,
+    image: TerminalImage,
+    closed: bool,
+}
+
+impl ImageViewerControl {
+    pub fn new(path: &Path, protocol: ImageProtocol) -> Result<Self> {
+        Ok(Self {
+            title: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            image: TerminalImage::open(path, protocol)?,
+            closed: false,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Control for ImageViewerControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if matches!(event.code, KeyCode::Esc | KeyCode::Char('q')) {
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        frame.render_widget(Clear, rect);
+        let border = Block::bordered()
+            .title(self.title.as_str())
+            .style(Style::default().fg(Color::Yellow));
+        let inner_area = border.inner(rect);
+        frame.render_widget(border, rect);
+        frame.render_widget(self.image.widget(), inner_area);
+        Ok(())
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,863 @@
+use super::control::Control;
+use super::image::{self, TerminalImage};
+use super::rich_text::{self, Highlighter, StyledText};
+use crate::config::{ImageProtocol, UiConfig};
+use crate::media_cache;
+use crate::runtime::{ChatReadState, DownloadProgress, DownloadState, Runtime, UploadProgress};
+use crate::storage::{OutboxMessage, OutboxState, StoredMessage};
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use grammers_client::types::media::Document;
+use grammers_client::types::Media;
+use grammers_tl_types as tl_types;
+use ratatui::layout::Rect;
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Clear, List, ListDirection, ListState, Paragraph};
+use ratatui::Frame;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    SelectNewer,
+    SelectOlder,
+    HalfPageNewer,
+    HalfPageOlder,
+    SelectNewest,
+    SelectOldest,
+    RetryFailed,
+    Reply,
+    Forward,
+    Edit,
+    DeleteForMe,
+    DeleteForEveryone,
+    Download,
+    CancelUpload,
+    ViewImage,
+}
+
+/// Action on the selected message, done by the parent control.
+pub enum MessageRequest {
+    Reply(StoredMessage),
+    Forward(StoredMessage),
+    Edit(StoredMessage),
+    ViewImage(PathBuf),
+}
+
+fn default_keymap() -> HashMap<KeyEvent, Action> {
+    HashMap::<KeyEvent, Action>::from([
+        (KeyCode::Char('j').into(), Action::SelectNewer),
+        (KeyCode::Char('k').into(), Action::SelectOlder),
+        (
+            KeyEvent::new(KeyCode::Char('d'), KeyModifiers::CONTROL),
+            Action::HalfPageNewer,
+        ),
+        (
+            KeyEvent::new(KeyCode::Char('u'), KeyModifiers::CONTROL),
+            Action::HalfPageOlder,
+        ),
+        (KeyCode::Char('G').into(), Action::SelectNewest),
+        (
+            KeyEvent::new(KeyCode::Char('G'), KeyModifiers::SHIFT),
+            Action::SelectNewest,
+        ),
+        (KeyCode::Char('g').into(), Action::SelectOldest),
+        (KeyCode::Char('R').into(), Action::RetryFailed),
+        (
+            KeyEvent::new(KeyCode::Char('R'), KeyModifiers::SHIFT),
+            Action::RetryFailed,
+        ),
+        (KeyCode::Char('r').into(), Action::Reply),
+        (KeyCode::Char('f').into(), Action::Forward),
+        (KeyCode::Char('e').into(), Action::Edit),
+        (KeyCode::Char('d').into(), Action::DeleteForMe),
+        (KeyCode::Char('D').into(), Action::DeleteForEveryone),
+        (
+            KeyEvent::new(KeyCode::Char('D'), KeyModifiers::SHIFT),
+            Action::DeleteForEveryone,
+        ),
+        (KeyCode::Char('s').into(), Action::Download),
+        (KeyCode::Char('x').into(), Action::CancelUpload),
+        (KeyCode::Char('v').into(), Action::ViewImage),
+    ])
+}
+
+// How many more messages are shown when user scrolls past the oldest one.
+const MESSAGES_PAGE_SIZE: usize = 50;
+const PROGRESS_BAR_WIDTH: usize = 20;
+// Maximum size of inline image previews in cells.
+const PREVIEW_MAX_WIDTH: u16 = 32;
+const PREVIEW_MAX_HEIGHT: u16 = 8;
+
+// Path of the image and maximum width of its preview in cells.
+type PreviewKey = (PathBuf, u16);
+
+pub struct MessagesListControl {
+    keymap: HashMap<KeyEvent, Action>,
+    app_runtime: Arc<Runtime>,
+    // Index 0 is the newest message, it is drawn at the bottom. Files and
+    // messages not sent yet go before all others.
+    list_state: ListState,
+    last_rect_height: u16,
+    // Chat, which messages were drawn last time.
+    shown_chat_id: Option<i64>,
+    // Maximum count of messages to load from storage for display.
+    shown_limit: usize,
+    last_shown_count: usize,
+    // Messages, IDs of uploads and count of outbox messages, drawn last
+    // time. Uploads go first, then outbox messages, then messages.
+    last_shown_messages: Vec<StoredMessage>,
+    last_upload_ids: Vec<u64>,
+    last_outbox_count: usize,
+    request: Option<MessageRequest>,
+    // ID of the message to delete and whether to delete it for everyone,
+    // while user confirms deletion.
+    pending_delete: Option<(i32, bool)>,
+    // Anchor message, which was selected last time.
+    shown_anchor: Option<i32>,
+    date_format: String,
+    image_protocol: ImageProtocol,
+    // Previews of downloaded images, as decoding is too slow to repeat on
+    // every redraw. None if the image failed to decode.
+    previews: HashMap<PreviewKey, Option<TerminalImage>>,
+    // Previews being decoded in the background, they come to the receiver.
+    decoding_previews: HashSet<PreviewKey>,
+    preview_sender: mpsc::Sender<(PreviewKey, Option<TerminalImage>)>,
+    preview_receiver: mpsc::Receiver<(PreviewKey, Option<TerminalImage>)>,
+    // Areas of previews, laid out by the last render, and previews drawn
+    // over them last time.
+    preview_rects: Vec<(PreviewKey, Rect)>,
+    shown_previews: HashSet<PreviewKey>,
+    highlighter: Highlighter,
+    // Formatted texts of the shown chat messages by their IDs and edit
+    // dates, as highlighting code is slow.
+    formatted_texts: HashMap<(i32, Option<i32>), StyledText>,
+}
+
+impl MessagesListControl {
+    pub fn new(app_runtime: Arc<Runtime>, ui_config: &UiConfig) -> Self {
+        let (preview_sender, preview_receiver) = mpsc::channel();
+        Self {
+            keymap: default_keymap(),
+            app_runtime,
+            list_state: ListState::default(),
+            last_rect_height: 0,
+            shown_chat_id: None,
+            shown_limit: MESSAGES_PAGE_SIZE,
+            last_shown_count: 0,
+            last_shown_messages: Vec::new(),
+            last_upload_ids: Vec::new(),
+            last_outbox_count: 0,
+            request: None,
+            pending_delete: None,
+            shown_anchor: None,
+            date_format: ui_config.date_format.clone(),
+            image_protocol: image::resolve_protocol(ui_config.image_protocol),
+            previews: HashMap::new(),
+            decoding_previews: HashSet::new(),
+            preview_sender,
+            preview_receiver,
+            preview_rects: Vec::new(),
+            shown_previews: HashSet::new(),
+            highlighter: Highlighter::new(),
+            formatted_texts: HashMap::new(),
+        }
+    }
+
+    fn handle_action(&mut self, action: Action) -> Result<()> {
+        // List is drawn bottom to top, so "older" means greater index.
+        match action {
+            Action::SelectNewer => {
+                self.list_state.select_previous();
+            }
+            Action::SelectOlder => {
+                self.list_state.select_next();
+            }
+            Action::HalfPageNewer => {
+                self.list_state.scroll_up_by(self.half_page());
+            }
+            Action::HalfPageOlder => {
+                self.list_state.scroll_down_by(self.half_page());
+            }
+            Action::SelectNewest => {
+                self.app_runtime.clear_active_chat_anchor();
+                self.list_state.select_first();
+            }
+            Action::SelectOldest => {
+                self.list_state.select_last();
+            }
+            Action::RetryFailed => {
+                self.app_runtime.retry_failed_messages()?;
+                return Ok(());
+            }
+            Action::Reply | Action::Forward | Action::Edit => {
+                self.request_action(action);
+                return Ok(());
+            }
+            Action::DeleteForMe | Action::DeleteForEveryone => {
+                if let Some(message) = self.selected_message() {
+                    // Messages of channels and megagroups can't be
+                    // deleted only for the user.
+                    let revoke = action == Action::DeleteForEveryone
+                        || matches!(message.raw.peer_id, tl_types::enums::Peer::Channel(_));
+                    self.pending_delete = Some((message.id(), revoke));
+                }
+                return Ok(());
+            }
+            Action::Download => {
+                if let Some(message) = self.selected_message() {
+                    self.app_runtime.download_media(message)?;
+                }
+                return Ok(());
+            }
+            Action::ViewImage => {
+                let Some(message) = self.selected_message() else {
+                    return Ok(());
+                };
+                match self.downloaded_image_path(message) {
+                    Some(path) => self.request = Some(MessageRequest::ViewImage(path)),
+                    None if Self::has_image(message) => self.app_runtime.download_media(message)?,
+                    None => {}
+                }
+                return Ok(());
+            }
+            Action::CancelUpload => {
+                if let Some(upload_id) = self
+                    .list_state
+                    .selected()
+                    .and_then(|index| self.last_upload_ids.get(index))
+                {
+                    self.app_runtime.cancel_upload(*upload_id);
+                }
+                return Ok(());
+            }
+        }
+        if let Some(selected) = self.list_state.selected() {
+            if selected >= self.last_shown_count.saturating_sub(1) {
+                self.show_older_messages()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns action on a message, requested by user since the last call.
+    pub fn take_request(&mut self) -> Option<MessageRequest> {
+        self.request.take()
+    }
+
+    fn request_action(&mut self, action: Action) {
+        let Some(message) = self.selected_message().cloned() else {
+            return;
+        };
+        self.request = match action {
+            Action::Reply => Some(MessageRequest::Reply(message)),
+            Action::Forward => Some(MessageRequest::Forward(message)),
+            // Only own messages can be edited.
+            Action::Edit if message.outgoing() => Some(MessageRequest::Edit(message)),
+            _ => None,
+        };
+    }
+
+    // Uploads and outbox messages are not selectable for actions, as they
+    // have no IDs on server yet.
+    fn selected_message(&self) -> Option<&StoredMessage> {
+        let index = self.list_state.selected()?;
+        let pending_count = self.last_upload_ids.len() + self.last_outbox_count;
+        self.last_shown_messages
+            .get(index.checked_sub(pending_count)?)
+    }
+
+    fn handle_delete_confirmation(&mut self, event: KeyEvent) -> Result<()> {
+        let Some((message_id, revoke)) = self.pending_delete.take() else {
+            return Ok(());
+        };
+        if matches!(event.code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+            self.app_runtime.delete_message(message_id, revoke)?;
+        }
+        Ok(())
+    }
+
+    fn render_delete_confirmation(&self, frame: &mut Frame, rect: Rect) {
+        let Some((message_id, revoke)) = self.pending_delete else {
+            return;
+        };
+        let text = if revoke {
+            format!("Delete message #{} for everyone? (y/n)", message_id)
+        } else {
+            format!("Delete message #{} for you? (y/n)", message_id)
+        };
+        let area = Rect::new(rect.x, rect.bottom().saturating_sub(1), rect.width, 1);
+        frame.render_widget(Clear, area);
+        frame.render_widget(
+            Paragraph::new(text).style(Style::new().black().on_yellow()),
+            area,
+        );
+    }
+
+    fn show_older_messages(&mut self) -> Result<()> {
+        if self.last_shown_count >= self.shown_limit {
+            // Storage may have more, just show them.
+            self.shown_limit += MESSAGES_PAGE_SIZE;
+        } else {
+            self.app_runtime.load_older_messages()?;
+        }
+        Ok(())
+    }
+
+    fn half_page(&self) -> u16 {
+        // Every message takes at least two lines.
+        std::cmp::max(1, self.last_rect_height / 4)
+    }
+
+    fn make_header(
+        message: &StoredMessage,
+        date_format: &str,
+        read_state: Option<&ChatReadState>,
+    ) -> Line<'static> {
+        let mut components = Vec::<Span>::new();
+        let sender_name = match message.sender() {
+            Some(sender) if !sender.name().is_empty() => sender.name().to_owned(),
+            Some(sender) => format!("#{}", sender.id()),
+            // Messages sent by us in private chats have no sender.
+            None if message.outgoing() => "You".to_string(),
+            None => message.chat().name().to_owned(),
+        };
+        let sender_style = if message.outgoing() {
+            Style::new().green().bold()
+        } else {
+            Style::new().cyan().bold()
+        };
+        components.push(Span::from(sender_name).style(sender_style));
+        let date = message.date().with_timezone(&chrono::Local);
+        components
+            .push(Span::from(format!(" {}", date.format(date_format))).style(Style::new().gray()));
+        if message.edit_date().is_some() && !message.edit_hide() {
+            components.push(Span::from(" (edited)").style(Style::new().dark_gray()));
+        }
+        if message.outgoing() {
+            let is_read = read_state.is_some_and(|state| message.id() <= state.read_outbox_max_id);
+            let ticks = if is_read { " ✓✓" } else { " ✓" };
+            components.push(Span::from(ticks).style(Style::new().green()));
+        }
+        Line::from(components)
+    }
+
+    fn make_markers(message: &StoredMessage) -> Vec<Line<'static>> {
+        let marker_style = Style::new().dark_gray().italic();
+        let mut result = Vec::new();
+        if let Some(tl_types::enums::MessageFwdHeader::Header(header)) = message.forward_header() {
+            let text = if let Some(from_name) = &header.from_name {
+                format!("Forwarded from {}", from_name)
+            } else {
+                "Forwarded".to_string()
+            };
+            result.push(Line::from(text).style(marker_style));
+        }
+        if let Some(reply_to_id) = message.reply_to_message_id() {
+            let text = format!("Reply to #{}", reply_to_id);
+            result.push(Line::from(text).style(marker_style));
+        }
+        result
+    }
+
+    // Returns description of the media with its download state.
+    fn make_media_line(&self, message: &StoredMessage) -> Line<'static> {
+        let media_style = Style::new().dark_gray();
+        let Some(media) = message.media() else {
+            return Line::from("[media]").style(media_style);
+        };
+        let mut components = vec![Span::from(format!("[{}]", Self::describe_media(&media)))];
+        let progress = match self.app_runtime.get_download_progress(&media) {
+            Ok(progress) => progress,
+            Err(e) => {
+                log::error!("Failed get download progress; Error {:?}", e);
+                None
+            }
+        };
+        if let Some(size) = media_cache::media_size(&media) {
+            components.push(Span::from(format!(" {}", Self::format_size(size))));
+        }
+        match progress {
+            Some(progress) => components.push(Self::make_download_state(&progress)),
+            None if media_cache::media_key(&media).is_some() => {
+                components.push(Span::from(" s to download"));
+            }
+            None => {}
+        }
+        Line::from(components).style(media_style)
+    }
+
+    fn text_key(message: &StoredMessage) -> (i32, Option<i32>) {
+        (message.id(), message.raw.edit_date)
+    }
+
+    // Formats texts of messages, which are not formatted yet.
+    fn update_formatted_texts(&mut self, messages: &[StoredMessage]) {
+        for message in messages {
+            self.formatted_texts
+                .entry(Self::text_key(message))
+                .or_insert_with(|| {
+                    rich_text::format(message.text(), message.entities(), &self.highlighter)
+                });
+        }
+    }
+
+    fn has_image(message: &StoredMessage) -> bool {
+        match message.media() {
+            Some(Media::Photo(_)) => true,
+            // Animated and video stickers are not decoded.
+            Some(Media::Sticker(sticker)) => sticker.document.mime_type() == Some("image/webp"),
+            _ => false,
+        }
+    }
+
+    // Returns path of the image of the message, if it is downloaded.
+    fn downloaded_image_path(&self, message: &StoredMessage) -> Option<PathBuf> {
+        if !Self::has_image(message) {
+            return None;
+        }
+        let media = message.media()?;
+        match self.app_runtime.get_download_progress(&media) {
+            Ok(Some(DownloadProgress {
+                state: DownloadState::Done(path),
+                ..
+            })) => Some(path),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Failed get download progress; Error {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn preview_width(list_width: u16) -> u16 {
+        std::cmp::min(list_width, PREVIEW_MAX_WIDTH)
+    }
+
+    // Takes previews, decoded since the last render, and starts decoding
+    // images of messages, which previews are not made yet. Previews of
+    // other widths are dropped, they are made again when width returns.
+    fn update_previews(&mut self, messages: &[StoredMessage], list_width: u16) {
+        while let Ok((key, preview)) = self.preview_receiver.try_recv() {
+            self.decoding_previews.remove(&key);
+            self.previews.insert(key, preview);
+        }
+        let width = Self::preview_width(list_width);
+        if self
+            .previews
+            .keys()
+            .any(|(_, key_width)| *key_width != width)
+        {
+            self.hide_images();
+            self.previews
+                .retain(|(_, key_width), _| *key_width == width);
+        }
+        for message in messages {
+            let Some(path) = self.downloaded_image_path(message) else {
+                continue;
+            };
+            let key = (path, width);
+            if self.previews.contains_key(&key) || self.decoding_previews.contains(&key) {
+                continue;
+            }
+            self.decoding_previews.insert(key.clone());
+            let sender = self.preview_sender.clone();
+            let protocol = self.image_protocol;
+            self.app_runtime.spawn_blocking(move || {
+                let (path, width) = &key;
+                let preview =
+                    match TerminalImage::open_preview(path, protocol, *width, PREVIEW_MAX_HEIGHT) {
+                        Ok(preview) => Some(preview),
+                        Err(e) => {
+                            // None is kept, so decoding is not retried.
+                            log::error!("Failed decode image {:?}; Error {:?}", path, e);
+                            None
+                        }
+                    };
+                // Fails only if the control is dropped meanwhile.
+                let _ = sender.send((key, preview));
+            });
+        }
+    }
+
+    fn describe_media(media: &Media) -> String {
+        match media {
+            Media::Photo(_) => "Photo".to_string(),
+            Media::Sticker(sticker) => format!("Sticker {}", sticker.emoji()),
+            Media::Document(document) => {
+                let mime_type = document.mime_type().unwrap_or_default();
+                let kind = if Self::is_voice(document) {
+                    "Voice message".to_string()
+                } else if mime_type.starts_with("video/") {
+                    "Video".to_string()
+                } else if mime_type.starts_with("audio/") {
+                    match (document.performer(), document.audio_title()) {
+                        (Some(performer), Some(title)) => {
+                            format!("Audio {} - {}", performer, title)
+                        }
+                        (_, Some(title)) => format!("Audio {}", title),
+                        _ => format!("Audio {}", document.name()),
+                    }
+                } else {
+                    format!("File {}", document.name())
+                };
+                match document.duration() {
+                    Some(duration) => {
+                        let seconds = duration.round() as u64;
+                        format!("{} {}:{:02}", kind, seconds / 60, seconds % 60)
+                    }
+                    None => kind,
+                }
+            }
+            Media::Contact(_) => "Contact".to_string(),
+            Media::Poll(_) => "Poll".to_string(),
+            Media::Geo(_) | Media::GeoLive(_) | Media::Venue(_) => "Location".to_string(),
+            Media::Dice(dice) => format!("Dice {}", dice.emoji()),
+            Media::WebPage(_) => "Web page".to_string(),
+            _ => "media".to_string(),
+        }
+    }
+
+    fn is_voice(document: &Document) -> bool {
+        let Some(tl_types::enums::Document::Document(raw)) = &document.raw.document else {
+            return false;
+        };
+        raw.attributes.iter().any(|attribute| {
+            matches!(attribute, tl_types::enums::DocumentAttribute::Audio(audio) if audio.voice)
+        })
+    }
+
+    fn make_download_state(progress: &DownloadProgress) -> Span<'static> {
+        match &progress.state {
+            DownloadState::Queued => Span::from(" queued"),
+            DownloadState::Downloading => {
+                let Some(total) = progress.total else {
+                    return Span::from(format!(
+                        " downloading {}",
+                        Self::format_size(progress.downloaded)
+                    ));
+                };
+                let ratio = (progress.downloaded as f64 / total as f64).clamp(0.0, 1.0);
+                Span::from(format!(" {}", Self::make_progress_bar(ratio)))
+                    .style(Style::new().yellow())
+            }
+            DownloadState::Done(path) => {
+                Span::from(format!(" saved to {}", path.display())).style(Style::new().green())
+            }
+            DownloadState::Failed => {
+                Span::from(" download failed, s to retry").style(Style::new().red())
+            }
+        }
+    }
+
+    fn make_progress_bar(ratio: f64) -> String {
+        let filled = (ratio * PROGRESS_BAR_WIDTH as f64).round() as usize;
+        format!(
+            "{}{} {}%",
+            "█".repeat(filled),
+            "░".repeat(PROGRESS_BAR_WIDTH - filled),
+            (ratio * 100.0).round()
+        )
+    }
+
+    fn format_size(size: u64) -> String {
+        const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+        let mut value = size as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} {}", size, UNITS[0])
+        } else {
+            format!("{:.1} {}", value, UNITS[unit])
+        }
+    }
+
+    fn make_divider(width: usize) -> Line<'static> {
+        let title = " New messages ";
+        let side_width = width.saturating_sub(title.len()) / 2;
+        let side = "─".repeat(side_width);
+        Line::from(format!("{}{}{}", side, title, side)).style(Style::new().yellow())
+    }
+
+    // Returns ID of the oldest incoming message, that was unread when the
+    // chat was opened. Messages are ordered newest first.
+    fn first_unread_id(
+        messages: &[StoredMessage],
+        read_state: Option<&ChatReadState>,
+    ) -> Option<i32> {
+        let read_inbox_max_id = read_state?.read_inbox_max_id;
+        messages
+            .iter()
+            .filter(|m| !m.outgoing() && m.id() > read_inbox_max_id)
+            .map(|m| m.id())
+            .min()
+    }
+
+    fn make_outbox_item(
+        &self,
+        message: &OutboxMessage,
+        width: usize,
+    ) -> ratatui::widgets::ListItem<'static> {
+        let mut components = vec![Span::from("You").style(Style::new().green().bold())];
+        if let Some(date) = chrono::DateTime::from_timestamp(i64::from(message.date), 0) {
+            let date = date.with_timezone(&chrono::Local);
+            components.push(
+                Span::from(format!(" {}", date.format(&self.date_format)))
+                    .style(Style::new().gray()),
+            );
+        }
+        components.push(match message.state {
+            OutboxState::Pending => Span::from(" sending...").style(Style::new().dark_gray()),
+            OutboxState::Failed => Span::from(" failed, R to retry").style(Style::new().red()),
+        });
+        let mut lines = vec![Line::from(components)];
+        lines.extend(rich_text::wrap(&rich_text::plain(&message.text), width));
+        ratatui::widgets::ListItem::new(Text::from(lines))
+    }
+
+    fn make_upload_item(
+        upload: &UploadProgress,
+        width: usize,
+    ) -> ratatui::widgets::ListItem<'static> {
+        let mut components = vec![Span::from("You").style(Style::new().green().bold())];
+        if upload.failed {
+            components
+                .push(Span::from(" failed, R to retry, x to cancel").style(Style::new().red()));
+        } else {
+            let ratio = if upload.total > 0 {
+                (upload.uploaded as f64 / upload.total as f64).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            components.push(Span::from(" sending ").style(Style::new().dark_gray()));
+            components
+                .push(Span::from(Self::make_progress_bar(ratio)).style(Style::new().yellow()));
+            components.push(Span::from(" x to cancel").style(Style::new().dark_gray()));
+        }
+        let mut lines = vec![
+            Line::from(components),
+            Line::from(format!(
+                "[{} {} {}]",
+                upload.kind.name(),
+                upload.file_name,
+                Self::format_size(upload.total)
+            ))
+            .style(Style::new().dark_gray()),
+        ];
+        lines.extend(rich_text::wrap(&rich_text::plain(&upload.caption), width));
+        ratatui::widgets::ListItem::new(Text::from(lines))
+    }
+
+    // Returns the item and area of the image preview relative to the item.
+    // Item has blank lines there, the preview is drawn over them later.
+    fn make_list_item(
+        &self,
+        message: &StoredMessage,
+        width: u16,
+        read_state: Option<&ChatReadState>,
+        with_divider: bool,
+    ) -> (
+        ratatui::widgets::ListItem<'static>,
+        Option<(PreviewKey, Rect)>,
+    ) {
+        let mut lines = Vec::new();
+        let mut preview_rect = None;
+        // Divider is a part of the item, so indices of items and messages
+        // stay the same.
+        if with_divider {
+            lines.push(Self::make_divider(width.into()));
+        }
+        lines.push(Self::make_header(message, &self.date_format, read_state));
+        lines.extend(Self::make_markers(message));
+        if message.raw.media.is_some() {
+            lines.push(self.make_media_line(message));
+            if let Some(path) = self.downloaded_image_path(message) {
+                let key = (path, Self::preview_width(width));
+                if let Some(Some(preview)) = self.previews.get(&key) {
+                    let fitted = preview.fit(Rect::new(0, 0, key.1, PREVIEW_MAX_HEIGHT));
+                    let rect = Rect::new(0, lines.len() as u16, fitted.width, fitted.height);
+                    lines.extend(std::iter::repeat_n(Line::default(), fitted.height.into()));
+                    preview_rect = Some((key, rect));
+                }
+            }
+        }
+        match self.formatted_texts.get(&Self::text_key(message)) {
+            Some(text) => lines.extend(rich_text::wrap(text, width.into())),
+            None => lines.extend(rich_text::wrap(
+                &rich_text::plain(message.text()),
+                width.into(),
+            )),
+        }
+        (
+            ratatui::widgets::ListItem::new(Text::from(lines)),
+            preview_rect,
+        )
+    }
+
+    // Finds where previews of the items are drawn, when the list is drawn
+    // bottom to top from its offset. Previews of items, not fitting in the
+    // rect, are not drawn.
+    fn layout_previews(
+        &mut self,
+        rect: Rect,
+        item_heights: &[u16],
+        item_previews: Vec<(usize, PreviewKey, Rect)>,
+    ) {
+        let mut item_tops = HashMap::new();
+        let mut bottom = rect.bottom();
+        for (index, height) in item_heights
+            .iter()
+            .enumerate()
+            .skip(self.list_state.offset())
+        {
+            match bottom.checked_sub(*height) {
+                Some(top) if top >= rect.top() => {
+                    item_tops.insert(index, top);
+                    bottom = top;
+                }
+                _ => break,
+            }
+        }
+        self.preview_rects = item_previews
+            .into_iter()
+            .filter_map(|(index, key, preview_rect)| {
+                let top = item_tops.get(&index)?;
+                let rect = Rect::new(
+                    rect.x + preview_rect.x,
+                    top + preview_rect.y,
+                    preview_rect.width,
+                    preview_rect.height,
+                );
+                Some((key, rect))
+            })
+            .collect();
+    }
+}
+
+impl Control for MessagesListControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if self.pending_delete.is_some() {
+            return self.handle_delete_confirmation(event);
+        }
+        if let Some(action) = self.keymap.get(&event) {
+            self.handle_action(*action)?;
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        let active_chat_id = self.app_runtime.get_active_chat().map(|chat| chat.id());
+        if active_chat_id != self.shown_chat_id {
+            self.shown_chat_id = active_chat_id;
+            self.list_state = ListState::default();
+            self.shown_limit = MESSAGES_PAGE_SIZE;
+            self.formatted_texts.clear();
+        }
+        if active_chat_id.is_none() {
+            self.preview_rects.clear();
+            let hint = Paragraph::new("Select a dialog to show messages")
+                .style(Style::new().dark_gray())
+                .centered();
+            frame.render_widget(hint, rect);
+            return Ok(());
+        }
+        self.last_rect_height = rect.height;
+        let messages = self
+            .app_runtime
+            .get_active_chat_messages(self.shown_limit)?;
+        let outbox = self.app_runtime.get_active_chat_outbox()?;
+        let uploads = self.app_runtime.get_active_chat_uploads();
+        let pending_count = uploads.len() + outbox.len();
+        self.last_shown_count = pending_count + messages.len();
+        self.last_outbox_count = outbox.len();
+        self.last_upload_ids = uploads.iter().rev().map(|upload| upload.id).collect();
+        let anchor = self.app_runtime.get_active_chat_anchor();
+        if anchor != self.shown_anchor {
+            self.shown_anchor = anchor;
+            if let Some(anchor) = anchor {
+                let index = messages.iter().position(|m| m.id() == anchor);
+                self.list_state
+                    .select(index.map(|index| index + pending_count));
+            }
+        }
+        self.update_previews(&messages, rect.width);
+        self.update_formatted_texts(&messages);
+        let read_state = self.app_runtime.get_active_chat_read_state()?;
+        let first_unread_id = Self::first_unread_id(&messages, read_state.as_ref());
+        let upload_items = uploads
+            .iter()
+            .rev()
+            .map(|upload| Self::make_upload_item(upload, rect.width.into()));
+        let outbox_items = outbox
+            .iter()
+            .rev()
+            .map(|m| self.make_outbox_item(m, rect.width.into()));
+        let mut item_previews = Vec::new();
+        let message_items = messages.iter().enumerate().map(|(index, m)| {
+            let with_divider = Some(m.id()) == first_unread_id;
+            let (item, preview) =
+                self.make_list_item(m, rect.width, read_state.as_ref(), with_divider);
+            if let Some((key, preview_rect)) = preview {
+                item_previews.push((index + pending_count, key, preview_rect));
+            }
+            item
+        });
+        let items: Vec<_> = upload_items
+            .chain(outbox_items)
+            .chain(message_items)
+            .collect();
+        let item_heights: Vec<u16> = items.iter().map(|item| item.height() as u16).collect();
+        let list = List::new(items)
+            .style(Style::new().white())
+            .highlight_style(Style::new().on_dark_gray())
+            .direction(ListDirection::BottomToTop);
+        frame.render_stateful_widget(list, rect, &mut self.list_state);
+        self.layout_previews(rect, &item_heights, item_previews);
+        self.last_shown_messages = messages;
+        self.render_delete_confirmation(frame, rect);
+        Ok(())
+    }
+
+    fn render_images(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        if self.pending_delete.is_some() {
+            // Confirmation is drawn over the list.
+            self.hide_images();
+            return Ok(());
+        }
+        let mut shown = HashSet::new();
+        for (key, preview_rect) in &self.preview_rects {
+            if preview_rect.intersection(rect) != *preview_rect {
+                continue;
+            }
+            if let Some(Some(preview)) = self.previews.get_mut(key) {
+                frame.render_widget(preview.widget(), *preview_rect);
+                shown.insert(key.clone());
+            }
+        }
+        for key in self.shown_previews.difference(&shown) {
+            if let Some(Some(preview)) = self.previews.get(key) {
+                preview.hide();
+            }
+        }
+        self.shown_previews = shown;
+        Ok(())
+    }
+
+    fn hide_images(&mut self) {
+        for key in self.shown_previews.drain() {
+            if let Some(Some(preview)) = self.previews.get(&key) {
+                preview.hide();
+            }
+        }
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        self.pending_delete.is_some()
+    }
+}
@@ -1,7 +1,21 @@
+mod chat_control;
+mod compose_control;
 mod control;
+mod dialog_picker_control;
 mod dialogs_list_control;
+mod image;
+mod image_viewer_control;
+mod messages_list_control;
+mod qr_login_control;
+mod rich_text;
+mod search_control;
+mod sign_in_control;
 mod two_panels_control;
 
+pub use chat_control::ChatControl;
 pub use control::Control;
 pub use dialogs_list_control::DialogsListControl;
+pub use qr_login_control::QrLoginControl;
+pub use search_control::SearchControl;
+pub use sign_in_control::SignInControl;
 pub use two_panels_control::TwoPanelsControl;
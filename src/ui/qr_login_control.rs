@@ -0,0 +1,153 @@
+use super::control::Control;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent};
+use qrcode::{Color as QrColor, QrCode};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear, Paragraph, Wrap};
+use ratatui::Frame;
+
+// Light modules around the code, required by scanners.
+const QUIET_ZONE: usize = 2;
+const MIN_DIALOG_WIDTH: u16 = 60;
+// Lines of text below the code.
+const TEXT_HEIGHT: u16 = 3;
+
+#[derive(PartialEq)]
+enum State {
+    Waiting,
+    PhoneRequested,
+    Cancelled,
+}
+
+// Shows login token as a QR code, to be scanned by Telegram app on another
+// device. P switches to sign in by phone number, Esc cancels sign in.
+pub struct QrLoginControl {
+    title: String,
+    // Each line covers two rows of QR code modules.
+    qr_lines: Vec<String>,
+    state: State,
+}
+
+impl QrLoginControl {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            qr_lines: Vec::new(),
+            state: State::Waiting,
+        }
+    }
+
+    pub fn set_token(&mut self, token: &[u8]) -> Result<()> {
+        let url = format!("tg://login?token={}", URL_SAFE_NO_PAD.encode(token));
+        let code = QrCode::new(url)?;
+        self.qr_lines = Self::make_qr_lines(&code);
+        Ok(())
+    }
+
+    pub fn is_phone_requested(&self) -> bool {
+        self.state == State::PhoneRequested
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state == State::Cancelled
+    }
+
+    fn make_qr_lines(code: &QrCode) -> Vec<String> {
+        let width = code.width();
+        let colors = code.to_colors();
+        let full_width = width + 2 * QUIET_ZONE;
+        let is_dark = |x: usize, y: usize| {
+            if x < QUIET_ZONE || y < QUIET_ZONE {
+                return false;
+            }
+            let (x, y) = (x - QUIET_ZONE, y - QUIET_ZONE);
+            x < width && y < width && colors[y * width + x] == QrColor::Dark
+        };
+        (0..full_width)
+            .step_by(2)
+            .map(|y| {
+                (0..full_width)
+                    .map(|x| match (is_dark(x, y), is_dark(x, y + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Control for QrLoginControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if self.state != State::Waiting {
+            return Ok(());
+        }
+        match event.code {
+            KeyCode::Esc => {
+                self.state = State::Cancelled;
+            }
+            KeyCode::Char('p') | KeyCode::Char('P') => {
+                self.state = State::PhoneRequested;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        let qr_width = self.qr_lines.first().map_or(0, |line| line.chars().count()) as u16;
+        let qr_height = self.qr_lines.len() as u16;
+        let dialog_width = std::cmp::max(qr_width, MIN_DIALOG_WIDTH) + 2;
+        let dialog_height = qr_height + TEXT_HEIGHT + 2;
+        let [dialog_area] = Layout::horizontal([Constraint::Length(dialog_width)])
+            .flex(Flex::Center)
+            .areas(rect);
+        let [dialog_area] = Layout::vertical([Constraint::Length(dialog_height)])
+            .flex(Flex::Center)
+            .areas(dialog_area);
+        frame.render_widget(Clear, dialog_area);
+        let border = Block::bordered()
+            .title(self.title.as_str())
+            .style(Style::default().fg(Color::Yellow));
+        let inner_area = border.inner(dialog_area);
+        frame.render_widget(border, dialog_area);
+        let [qr_area, text_area] =
+            Layout::vertical([Constraint::Length(qr_height), Constraint::Min(0)]).areas(inner_area);
+
+        if qr_area.width < qr_width || qr_area.height < qr_height {
+            frame.render_widget(
+                Paragraph::new("Terminal is too small to show QR code")
+                    .style(Style::new().red())
+                    .wrap(Wrap { trim: true }),
+                inner_area,
+            );
+        } else {
+            let [qr_area] = Layout::horizontal([Constraint::Length(qr_width)])
+                .flex(Flex::Center)
+                .areas(qr_area);
+            // Colors are fixed, so the code is scannable with any terminal theme.
+            let lines: Vec<Line> = self
+                .qr_lines
+                .iter()
+                .map(|line| Line::from(line.as_str()))
+                .collect();
+            frame.render_widget(
+                Paragraph::new(lines).style(Style::new().fg(Color::Black).bg(Color::White)),
+                qr_area,
+            );
+        }
+        let text = vec![
+            Line::from("Scan with Telegram app: Settings > Devices > Link Desktop Device")
+                .style(Style::new().white()),
+            Line::from("P to sign in by phone number, Esc to quit").style(Style::new().dark_gray()),
+        ];
+        frame.render_widget(Paragraph::new(text).wrap(Wrap { trim: true }), text_area);
+        Ok(())
+    }
+}
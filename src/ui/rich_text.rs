@@ -0,0 +1,303 @@
+use grammers_tl_types as tl_types;
+use ratatui::prelude::*;
+use ratatui::style::{Modifier, Style};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use unicode_width::UnicodeWidthChar;
+
+const THEME_NAME: &str = "base16-ocean.dark";
+
+/// Text with style of every character.
+pub type StyledText = Vec<(char, Style)>;
+
+/// Colors code of pre blocks by their language.
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        let mut themes = ThemeSet::load_defaults();
+        Self {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes.themes.remove(THEME_NAME).unwrap_or_default(),
+        }
+    }
+
+    // Returns style of every character of the code, or None if the
+    // language is unknown.
+    fn highlight(&self, code: &str, language: &str) -> Option<Vec<Style>> {
+        let syntax = self.syntaxes.find_syntax_by_token(language)?;
+        let mut highlighter = HighlightLines::new(syntax, &self.theme);
+        let mut result = Vec::new();
+        for line in LinesWithEndings::from(code) {
+            let regions = match highlighter.highlight_line(line, &self.syntaxes) {
+                Ok(regions) => regions,
+                Err(e) => {
+                    log::error!("Failed highlight {} code; Error {:?}", language, e);
+                    return None;
+                }
+            };
+            for (region_style, text) in regions {
+                let style = Self::convert_style(region_style);
+                result.extend(text.chars().map(|_| style));
+            }
+        }
+        Some(result)
+    }
+
+    // Background of the theme is dropped, so code blocks look like the
+    // rest of the messages.
+    fn convert_style(style: syntect::highlighting::Style) -> Style {
+        let color = style.foreground;
+        let mut result = Style::new().fg(Color::Rgb(color.r, color.g, color.b));
+        if style.font_style.contains(FontStyle::BOLD) {
+            result = result.add_modifier(Modifier::BOLD);
+        }
+        if style.font_style.contains(FontStyle::ITALIC) {
+            result = result.add_modifier(Modifier::ITALIC);
+        }
+        if style.font_style.contains(FontStyle::UNDERLINE) {
+            result = result.add_modifier(Modifier::UNDERLINED);
+        }
+        result
+    }
+}
+
+/// Returns the text without formatting.
+pub fn plain(text: &str) -> StyledText {
+    text.chars().map(|c| (c, Style::new())).collect()
+}
+
+/// Returns the text styled by its entities. Offsets of entities are in
+/// UTF-16 code units, as Telegram counts them.
+pub fn format(
+    text: &str,
+    entities: &[tl_types::enums::MessageEntity],
+    highlighter: &Highlighter,
+) -> StyledText {
+    let mut result = plain(text);
+    // UTF-16 offset of every character and of the text end.
+    let mut offsets = Vec::with_capacity(result.len() + 1);
+    let mut offset = 0;
+    for c in text.chars() {
+        offsets.push(offset);
+        offset += c.len_utf16();
+    }
+    offsets.push(offset);
+    // Entities come from other clients and may point outside of the text,
+    // so their ranges are clamped to it and empty ones are skipped.
+    let chars_count = result.len();
+    let char_range = |entity: &tl_types::enums::MessageEntity| {
+        let to_char_index = |utf16_offset: i32| {
+            let utf16_offset = usize::try_from(utf16_offset).unwrap_or(0);
+            std::cmp::min(
+                offsets.partition_point(|offset| *offset < utf16_offset),
+                chars_count,
+            )
+        };
+        let start = to_char_index(entity.offset());
+        let end = to_char_index(entity.offset().saturating_add(entity.length()));
+        (start < end).then_some(start..end)
+    };
+    for entity in entities {
+        let Some(range) = char_range(entity) else {
+            continue;
+        };
+        if let Some(style) = entity_style(entity) {
+            for (_, char_style) in &mut result[range.clone()] {
+                *char_style = char_style.patch(style);
+            }
+        }
+        if let tl_types::enums::MessageEntity::Pre(pre) = entity {
+            let code: String = result[range.clone()].iter().map(|(c, _)| c).collect();
+            if let Some(code_styles) = highlighter.highlight(&code, &pre.language) {
+                for ((_, char_style), code_style) in result[range].iter_mut().zip(code_styles) {
+                    *char_style = char_style.patch(code_style);
+                }
+            }
+        }
+    }
+    // Links are added after texts, that hide them, from the end, so
+    // ranges of the remaining ones stay valid.
+    let mut links: Vec<_> = entities
+        .iter()
+        .filter_map(|entity| match entity {
+            tl_types::enums::MessageEntity::TextUrl(text_url) => {
+                Some((char_range(entity)?.end, text_url.url.as_str()))
+            }
+            _ => None,
+        })
+        .collect();
+    links.sort_by_key(|(end, _)| std::cmp::Reverse(*end));
+    let link_style = Style::new().dark_gray();
+    for (end, url) in links {
+        let link: StyledText = format!(" ({})", url)
+            .chars()
+            .map(|c| (c, link_style))
+            .collect();
+        result.splice(end..end, link);
+    }
+    result
+}
+
+fn entity_style(entity: &tl_types::enums::MessageEntity) -> Option<Style> {
+    use tl_types::enums::MessageEntity;
+    let style = match entity {
+        MessageEntity::Bold(_) => Style::new().bold(),
+        MessageEntity::Italic(_) => Style::new().italic(),
+        MessageEntity::Underline(_) => Style::new().underlined(),
+        MessageEntity::Strike(_) => Style::new().crossed_out(),
+        // Hidden the same color as its background.
+        MessageEntity::Spoiler(_) => Style::new().dark_gray().on_dark_gray(),
+        // Code in known languages is colored over this.
+        MessageEntity::Code(_) | MessageEntity::Pre(_) => Style::new().yellow(),
+        MessageEntity::Url(_)
+        | MessageEntity::TextUrl(_)
+        | MessageEntity::Email(_)
+        | MessageEntity::Phone(_) => Style::new().blue().underlined(),
+        MessageEntity::Mention(_)
+        | MessageEntity::MentionName(_)
+        | MessageEntity::InputMessageEntityMentionName(_)
+        | MessageEntity::Hashtag(_)
+        | MessageEntity::Cashtag(_)
+        | MessageEntity::BotCommand(_) => Style::new().cyan(),
+        MessageEntity::Blockquote(_) => Style::new().gray().italic(),
+        _ => return None,
+    };
+    Some(style)
+}
+
+/// Splits the text into lines not wider than `width`.
+pub fn wrap(text: &[(char, Style)], width: usize) -> Vec<Line<'static>> {
+    let mut result = Vec::new();
+    let mut source_lines: Vec<&[(char, Style)]> = text.split(|(c, _)| *c == '\n').collect();
+    // Same as str::lines(), text ending with new line has no empty line
+    // after it.
+    if source_lines.last().is_some_and(|line| line.is_empty()) {
+        source_lines.pop();
+    }
+    for source_line in source_lines {
+        let mut line = Vec::new();
+        let mut line_width = 0;
+        for (c, style) in source_line {
+            if *c == '\r' {
+                continue;
+            }
+            let char_width = c.width().unwrap_or(0);
+            if line_width + char_width > width && !line.is_empty() {
+                result.push(make_line(&std::mem::take(&mut line)));
+                line_width = 0;
+            }
+            line.push((*c, *style));
+            line_width += char_width;
+        }
+        result.push(make_line(&line));
+    }
+    result
+}
+
+// Joins characters of the same style into spans.
+fn make_line(chars: &[(char, Style)]) -> Line<'static> {
+    let spans: Vec<Span> = chars
+        .chunk_by(|(_, first), (_, second)| first == second)
+        .map(|chunk| {
+            let text: String = chunk.iter().map(|(c, _)| c).collect();
+            Span::styled(text, chunk[0].1)
+        })
+        .collect();
+    Line::from(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bold(offset: i32, length: i32) -> tl_types::enums::MessageEntity {
+        tl_types::types::MessageEntityBold { offset, length }.into()
+    }
+
+    fn span_texts(line: &Line) -> Vec<String> {
+        line.spans
+            .iter()
+            .map(|span| span.content.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn entity_offsets_are_utf16() {
+        let highlighter = Highlighter::new();
+        // Emoji takes two UTF-16 code units.
+        let text = format("😀 bold rest", &[bold(3, 4)], &highlighter);
+        let lines = wrap(&text, 80);
+        assert_eq!(span_texts(&lines[0]), vec!["😀 ", "bold", " rest"]);
+        assert_eq!(lines[0].spans[1].style, Style::new().bold());
+    }
+
+    #[test]
+    fn text_url_is_shown_after_text() {
+        let highlighter = Highlighter::new();
+        let entities = [
+            tl_types::types::MessageEntityTextUrl {
+                offset: 0,
+                length: 4,
+                url: "https://example.com".to_string(),
+            }
+            .into(),
+            bold(5, 4),
+        ];
+        let text = format("link more", &entities, &highlighter);
+        let line: String = text.iter().map(|(c, _)| c).collect();
+        assert_eq!(line, "link (https://example.com) more");
+        let lines = wrap(&text, 80);
+        assert_eq!(lines[0].spans.last().unwrap().style, Style::new().bold());
+    }
+
+    #[test]
+    fn entities_outside_of_text_are_clamped() {
+        let highlighter = Highlighter::new();
+        let entities = [
+            bold(6, 100),
+            bold(50, 4),
+            bold(2, -1),
+            bold(-3, 4),
+            tl_types::types::MessageEntityTextUrl {
+                offset: 20,
+                length: 4,
+                url: "https://example.com".to_string(),
+            }
+            .into(),
+        ];
+        let text = format("plain bold", &entities, &highlighter);
+        let lines = wrap(&text, 80);
+        assert_eq!(span_texts(&lines[0]), vec!["p", "lain ", "bold"]);
+        assert_eq!(lines[0].spans[0].style, Style::new().bold());
+        assert_eq!(lines[0].spans[2].style, Style::new().bold());
+    }
+
+    #[test]
+    fn pre_block_is_highlighted() {
+        let highlighter = Highlighter::new();
+        let code = "fn main() {}";
+        let entities = [tl_types::types::MessageEntityPre {
+            offset: 0,
+            length: code.len() as i32,
+            language: "rust".to_string(),
+        }
+        .into()];
+        let text = format(code, &entities, &highlighter);
+        let lines = wrap(&text, 80);
+        // Keyword and name of the function have different colors.
+        assert_ne!(lines[0].spans[0].style, lines[0].spans[2].style);
+    }
+
+    #[test]
+    fn long_lines_are_wrapped() {
+        let lines = wrap(&plain("abcdef\nxy\n"), 4);
+        let texts: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        assert_eq!(texts, vec!["abcd", "ef", "xy"]);
+    }
+}
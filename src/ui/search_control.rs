@@ -0,0 +1,238 @@
+use super::control::Control;
+use crate::config::UiConfig;
+use crate::runtime::Runtime;
+use crate::storage::StoredMessage;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use grammers_client::types::Chat;
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear, List, ListState, Paragraph};
+use ratatui::Frame;
+use std::sync::Arc;
+use unicode_width::UnicodeWidthStr;
+
+const SEARCH_RESULTS_LIMIT: usize = 100;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SearchScope {
+    // Cached messages, searched while typing.
+    Local,
+    // Server search in all chats, started by Enter.
+    Global,
+    // Server search in the active chat, started by Enter.
+    ActiveChat,
+}
+
+impl SearchScope {
+    fn next(self) -> Self {
+        match self {
+            SearchScope::Local => SearchScope::Global,
+            SearchScope::Global => SearchScope::ActiveChat,
+            SearchScope::ActiveChat => SearchScope::Local,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            SearchScope::Local => "Search in cache",
+            SearchScope::Global => "Search on server",
+            SearchScope::ActiveChat => "Search on server in active chat",
+        }
+    }
+}
+
+struct SearchResult {
+    chat: Chat,
+    message_id: i32,
+    display_content: Text<'static>,
+}
+
+// Popup with search query input and list of found messages.
+// Tab switches search scope, Enter starts server search or jumps to the
+// selected message, Esc closes the popup.
+pub struct SearchControl {
+    app_runtime: Arc<Runtime>,
+    scope: SearchScope,
+    query: String,
+    // Query of the started server search.
+    server_query: Option<String>,
+    server_search_in_progress: bool,
+    results: Vec<SearchResult>,
+    list_state: ListState,
+    closed: bool,
+    date_format: String,
+}
+
+impl SearchControl {
+    pub fn new(app_runtime: Arc<Runtime>, ui_config: &UiConfig) -> Self {
+        Self {
+            app_runtime,
+            scope: SearchScope::Local,
+            query: String::new(),
+            server_query: None,
+            server_search_in_progress: false,
+            results: Vec::new(),
+            list_state: ListState::default(),
+            closed: false,
+            date_format: ui_config.date_format.clone(),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn on_query_changed(&mut self) -> Result<()> {
+        if self.scope == SearchScope::Local {
+            let messages = self
+                .app_runtime
+                .search_local_messages(&self.query, SEARCH_RESULTS_LIMIT)?;
+            self.set_results(messages);
+        }
+        Ok(())
+    }
+
+    fn set_results(&mut self, messages: Vec<StoredMessage>) {
+        self.results = messages
+            .iter()
+            .map(|m| Self::make_result(m, &self.date_format))
+            .collect();
+        if self.results.is_empty() {
+            self.list_state.select(None);
+        } else if self.list_state.selected().is_none() {
+            self.list_state.select_first();
+        }
+    }
+
+    fn switch_scope(&mut self) -> Result<()> {
+        self.scope = self.scope.next();
+        self.server_query = None;
+        self.results.clear();
+        self.list_state = ListState::default();
+        self.on_query_changed()
+    }
+
+    fn handle_enter(&mut self) -> Result<()> {
+        let is_new_server_query = self.scope != SearchScope::Local
+            && self.server_query.as_deref() != Some(self.query.as_str());
+        if is_new_server_query {
+            if self.query.trim().is_empty() {
+                return Ok(());
+            }
+            self.app_runtime
+                .start_server_search(&self.query, self.scope == SearchScope::ActiveChat)?;
+            self.server_query = Some(self.query.clone());
+            self.list_state = ListState::default();
+            Ok(())
+        } else {
+            self.jump_to_selected()
+        }
+    }
+
+    fn make_result(message: &StoredMessage, date_format: &str) -> SearchResult {
+        let chat = message.chat();
+        let date = message.date().with_timezone(&chrono::Local);
+        let header = Line::from(vec![
+            Span::from(chat.name().to_owned()).style(Style::new().cyan().bold()),
+            Span::from(format!(" {}", date.format(date_format))).style(Style::new().gray()),
+        ]);
+        let first_line = message.text().lines().next().unwrap_or_default().to_owned();
+        SearchResult {
+            chat,
+            message_id: message.id(),
+            display_content: Text::from(vec![header, Line::from(first_line)]),
+        }
+    }
+
+    fn jump_to_selected(&mut self) -> Result<()> {
+        if let Some(selected) = self.list_state.selected() {
+            let result = &self.results[selected];
+            self.app_runtime
+                .jump_to_message(result.chat.clone(), result.message_id)?;
+            self.closed = true;
+        }
+        Ok(())
+    }
+}
+
+impl Control for SearchControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        let with_control = event.modifiers.contains(KeyModifiers::CONTROL);
+        match event.code {
+            KeyCode::Esc => {
+                self.closed = true;
+            }
+            KeyCode::Enter => {
+                self.handle_enter()?;
+            }
+            KeyCode::Tab => {
+                self.switch_scope()?;
+            }
+            KeyCode::Down => self.list_state.select_next(),
+            KeyCode::Char('n') if with_control => self.list_state.select_next(),
+            KeyCode::Up => self.list_state.select_previous(),
+            KeyCode::Char('p') if with_control => self.list_state.select_previous(),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.on_query_changed()?;
+            }
+            KeyCode::Char(c) if !with_control => {
+                self.query.push(c);
+                self.on_query_changed()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        if self.scope != SearchScope::Local && self.server_query.is_some() {
+            // Server search results arrive while the popup is shown.
+            let (messages, in_progress) = self.app_runtime.get_server_search_results();
+            self.server_search_in_progress = in_progress;
+            self.set_results(messages);
+        }
+        let title = if self.server_query.is_some() && self.server_search_in_progress {
+            format!("{} (searching...)", self.scope.title())
+        } else {
+            self.scope.title().to_string()
+        };
+        frame.render_widget(Clear, rect);
+        let border = Block::bordered()
+            .title(title)
+            .style(Style::default().fg(Color::Yellow));
+        let inner_area = border.inner(rect);
+        frame.render_widget(border, rect);
+        let [query_area, results_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner_area);
+
+        let prompt = "> ";
+        let query_line = format!("{}{}", prompt, self.query);
+        frame.render_widget(
+            Paragraph::new(query_line.clone()).style(Style::new().white()),
+            query_area,
+        );
+        let cursor_x = query_area.x + query_line.width() as u16;
+        frame.set_cursor_position(Position::new(
+            std::cmp::min(cursor_x, query_area.right().saturating_sub(1)),
+            query_area.y,
+        ));
+
+        let items: Vec<_> = self
+            .results
+            .iter()
+            .map(|r| ratatui::widgets::ListItem::new(r.display_content.clone()))
+            .collect();
+        let list = List::new(items)
+            .style(Style::new().white())
+            .highlight_style(Style::new().on_dark_gray());
+        frame.render_stateful_widget(list, results_area, &mut self.list_state);
+        Ok(())
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,155 @@
+use super::control::Control;
+use color_eyre::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::layout::{Constraint, Flex, Layout, Position, Rect};
+use ratatui::prelude::*;
+use ratatui::style::Style;
+use ratatui::widgets::{Block, Clear, Paragraph, Wrap};
+use ratatui::Frame;
+use unicode_width::UnicodeWidthStr;
+
+const DIALOG_WIDTH: u16 = 60;
+const DIALOG_HEIGHT: u16 = 9;
+
+#[derive(PartialEq)]
+enum State {
+    Editing,
+    Submitted,
+    Cancelled,
+}
+
+// Asks for one value during sign in: phone number, code or password.
+// Enter submits the value, Esc cancels sign in.
+pub struct SignInControl {
+    title: String,
+    label: String,
+    hint: Option<String>,
+    error: Option<String>,
+    masked: bool,
+    input: String,
+    state: State,
+}
+
+impl SignInControl {
+    pub fn new(title: &str, label: &str, masked: bool) -> Self {
+        Self {
+            title: title.to_string(),
+            label: label.to_string(),
+            hint: None,
+            error: None,
+            masked,
+            input: String::new(),
+            state: State::Editing,
+        }
+    }
+
+    pub fn set_hint(&mut self, hint: Option<String>) {
+        self.hint = hint;
+    }
+
+    /// Clears entered value and asks for it again, showing `error`.
+    pub fn retry(&mut self, error: String) {
+        self.error = Some(error);
+        self.input.clear();
+        self.state = State::Editing;
+    }
+
+    pub fn is_submitted(&self) -> bool {
+        self.state == State::Submitted
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state == State::Cancelled
+    }
+
+    pub fn value(&self) -> &str {
+        self.input.trim()
+    }
+}
+
+impl Control for SignInControl {
+    fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if self.state != State::Editing {
+            return Ok(());
+        }
+        match event.code {
+            KeyCode::Esc => {
+                self.state = State::Cancelled;
+            }
+            KeyCode::Enter if !self.value().is_empty() => {
+                self.state = State::Submitted;
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) if !event.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.input.push(c);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        let [dialog_area] = Layout::horizontal([Constraint::Length(DIALOG_WIDTH)])
+            .flex(Flex::Center)
+            .areas(rect);
+        let [dialog_area] = Layout::vertical([Constraint::Length(DIALOG_HEIGHT)])
+            .flex(Flex::Center)
+            .areas(dialog_area);
+        frame.render_widget(Clear, dialog_area);
+        let border = Block::bordered()
+            .title(self.title.as_str())
+            .style(Style::default().fg(Color::Yellow));
+        let inner_area = border.inner(dialog_area);
+        frame.render_widget(border, dialog_area);
+        let [label_area, input_area, hint_area, status_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Min(0),
+        ])
+        .areas(inner_area);
+
+        frame.render_widget(
+            Paragraph::new(self.label.as_str()).style(Style::new().white()),
+            label_area,
+        );
+        let shown_input = if self.masked {
+            "*".repeat(self.input.chars().count())
+        } else {
+            self.input.clone()
+        };
+        let input_line = format!("> {}", shown_input);
+        if self.state == State::Editing {
+            let cursor_x = input_area.x + input_line.width() as u16;
+            frame.set_cursor_position(Position::new(
+                std::cmp::min(cursor_x, input_area.right().saturating_sub(1)),
+                input_area.y,
+            ));
+        }
+        frame.render_widget(
+            Paragraph::new(input_line).style(Style::new().white().bold()),
+            input_area,
+        );
+        if let Some(hint) = self.hint.as_ref() {
+            frame.render_widget(
+                Paragraph::new(format!("Hint: {}", hint)).style(Style::new().dark_gray()),
+                hint_area,
+            );
+        }
+        let status = if self.state == State::Submitted {
+            Paragraph::new("Please wait...").style(Style::new().gray())
+        } else if let Some(error) = self.error.as_ref() {
+            Paragraph::new(error.as_str()).style(Style::new().red())
+        } else {
+            Paragraph::new("Enter to continue, Esc to quit").style(Style::new().dark_gray())
+        };
+        frame.render_widget(status.wrap(Wrap { trim: true }), status_area);
+        Ok(())
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        true
+    }
+}
@@ -55,6 +55,7 @@ impl TwoPanelsControl {
         right_child: Box<dyn Control>,
         left_title: Option<String>,
         right_title: Option<String>,
+        left_width_percent: u16,
     ) -> Self {
         Self {
             left_child,
@@ -62,7 +63,7 @@ impl TwoPanelsControl {
             left_title,
             right_title,
             focused: Focused::Left,
-            left_width_percent: 50,
+            left_width_percent,
             keymap: default_keymap(),
         }
     }
@@ -87,6 +88,13 @@ impl TwoPanelsControl {
         Ok(())
     }
 
+    fn focused_child(&mut self) -> &mut Box<dyn Control> {
+        match self.focused {
+            Focused::Left => &mut self.left_child,
+            Focused::Right => &mut self.right_child,
+        }
+    }
+
     fn compute_child_rects(&self, rect: Rect) -> Option<(Rect, Rect)> {
         // 2 positions for left and right borders and at least one for
         // inner content.
@@ -112,13 +120,13 @@ impl TwoPanelsControl {
 
 impl Control for TwoPanelsControl {
     fn handle_keyboard(&mut self, event: KeyEvent) -> Result<()> {
+        if self.captures_keyboard() {
+            return self.focused_child().handle_keyboard(event);
+        }
         if let Some(action) = self.keymap.get(&event) {
             self.handle_action(*action)
         } else {
-            match self.focused {
-                Focused::Left => self.left_child.handle_keyboard(event),
-                Focused::Right => self.right_child.handle_keyboard(event),
-            }
+            self.focused_child().handle_keyboard(event)
         }
     }
 
@@ -162,4 +170,27 @@ impl Control for TwoPanelsControl {
         self.right_child.render(frame, right_area.inner(margins))?;
         Ok(())
     }
+
+    fn render_images(&mut self, frame: &mut Frame, rect: Rect) -> Result<()> {
+        let Some((left_area, right_area)) = self.compute_child_rects(rect) else {
+            return Ok(());
+        };
+        let margins = Margin::new(1, 1);
+        self.left_child
+            .render_images(frame, left_area.inner(margins))?;
+        self.right_child
+            .render_images(frame, right_area.inner(margins))
+    }
+
+    fn hide_images(&mut self) {
+        self.left_child.hide_images();
+        self.right_child.hide_images();
+    }
+
+    fn captures_keyboard(&self) -> bool {
+        match self.focused {
+            Focused::Left => self.left_child.captures_keyboard(),
+            Focused::Right => self.right_child.captures_keyboard(),
+        }
+    }
 }